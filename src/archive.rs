@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use crate::object::{readByte, readCount, readString, writeCount, writeString, ObjectFile};
+
+/// Archives start with this magic and a format version byte, followed by the
+/// member count and for every member its name, its length in bytes and the
+/// object file itself.
+pub const archiveMagic: &[u8; 4] = b"NISA";
+const archiveVersion: u8 = 1;
+
+/// A static library: a bundle of object files the linker pulls members from
+/// only when they define a symbol that is still unresolved.
+#[derive(Debug)]
+pub struct Archive {
+    pub members: Vec<(String, ObjectFile)>,
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive { members: vec![] }
+    }
+
+    pub fn addMember(&mut self, name: &str, object: ObjectFile) {
+        self.members.push((name.to_string(), object));
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(archiveMagic)?;
+        writer.write_all(&[archiveVersion])?;
+        writeCount(writer, self.members.len())?;
+        for (name, object) in &self.members {
+            let mut bytes = vec![];
+            object.write(&mut bytes)?;
+            writeString(writer, name)?;
+            writeCount(writer, bytes.len())?;
+            writer.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Archive> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != archiveMagic {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a NIS archive"));
+        }
+        let version = readByte(reader)?;
+        if version != archiveVersion {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported archive version {}", version),
+            ));
+        }
+        let mut archive = Archive::new();
+        for _ in 0..readCount(reader)? {
+            let name = readString(reader)?;
+            let length = readCount(reader)?;
+            let mut member = reader.take(length as u64);
+            let object = ObjectFile::read(&mut member).map_err(|error| {
+                io::Error::new(error.kind(), format!("member {}: {}", name, error))
+            })?;
+            archive.addMember(&name, object);
+        }
+        Ok(archive)
+    }
+
+    pub fn writeToFile(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn readFromFile(path: &str) -> io::Result<Archive> {
+        Archive::read(&mut BufReader::new(File::open(path)?))
+    }
+}
@@ -1,41 +1,207 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{io, process};
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
-use std::path::Path;
-use crate::{parse_include_file, stringInstructionsToU8, stringToReg, IntermediateLanguage, IntermediateLanguageLine, Line, Parcher};
+use std::time::{Duration, Instant};
+use crate::fault::VmFault;
+use crate::heap::Heap;
+use crate::machine::{endOfInput, Console, Machine};
+use crate::instructions::{self as op, byMnemonic, mnemonicOf, OperandKind};
+use crate::modules::{loadModuleFailed, loadModuleInterrupt};
+use crate::object::{readByte, readCount, readString, writeCount, writeString};
+use crate::snapshot::programHash;
+use crate::{
+    fpRegister, interruptRegister, ipRegister, lineSize, lrRegister, registerCount, resultRegister, spRegister,
+    stringToReg, Addressing, IntermediateLanguage, IntermediateLanguageLine, Line,
+    SourceLocation,
+};
+
+/// Bytecode files start with this magic, a format version byte, the entry
+/// address as a little-endian u64 (`noEntry` for library modules), the
+/// exported symbols, which modules loaded at runtime can import, and the
+/// operands holding code addresses, which are rebased when the file is loaded
+/// as a module.
+pub const byteCodeMagic: &[u8; 4] = b"NISB";
+const byteCodeVersion: u8 = 4;
+const noEntry: u64 = u64::MAX;
+pub const defaultStackSize: usize = 1024;
+
+/// Which operand of an instruction a relocation patches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Arg1,
+    Arg2,
+}
+
+impl Relocation {
+    /// Patches the operand with the symbol's address plus the addend.
+    pub fn apply(&self, line: &mut Line, target: usize) {
+        let value = (target as u64).wrapping_add(self.addend);
+        match self.operand {
+            Operand::Arg1 => line.arg1 = value,
+            Operand::Arg2 => line.arg2 = value,
+        }
+    }
+}
+
+/// A parsed memory operand: its addressing and the numeric part of its displacement.
+struct MemoryOperand {
+    addressing: Addressing,
+    displacement: u64,
+}
+
+/// Parses a decimal or `0x` hexadecimal number.
+pub fn parseNumber(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u64>().ok(),
+    }
+}
+
+/// Parses a float literal. It needs a decimal point, so `2` stays an integer
+/// and `2.0` is the f64 bit pattern of 2.
+fn parseFloat(text: &str) -> Option<f64> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    if !text.contains('.') || !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse::<f64>().ok()
+}
+
+/// Where `call` keeps the return address that `ret` uses. `call` also copies
+/// it to `lr` in every mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallMode {
+    /// A call stack outside `mem` that the program can't see.
+    Hidden,
+    /// Pushed onto the `sp` stack in `mem`.
+    Stack,
+    /// Only in `lr`; a callee that calls further saves `lr` itself.
+    Link,
+}
+
+/// Bounds on a run, each ending it with its own fault when reached. `None`
+/// leaves that resource unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Instructions executed, counting the one that would go over.
+    pub maxInstructions: Option<u64>,
+    /// Words of heap live at once, allocated through interrupts 1 and 8.
+    pub maxHeapWords: Option<usize>,
+    /// Calls that haven't returned yet.
+    pub maxCallDepth: Option<usize>,
+    /// Wall clock time, checked every `timeoutCheckInterval` instructions.
+    pub timeout: Option<Duration>,
+}
+
+/// How many instructions run between looks at the clock for `Limits::timeout`.
+const timeoutCheckInterval: u64 = 1024;
+
+/// Ties an instruction operand to the label whose address belongs in it. The
+/// operand is left as 0 by the emit pass and patched once every label has an address.
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub index: usize,
+    pub operand: Operand,
+    /// Empty for relocations read from a bytecode file, which are relative to
+    /// the start of its code.
+    pub symbol: String,
+    /// Added to the symbol's address, e.g. the 8 in `[label+8]`.
+    pub addend: u64,
+    /// Where the reference was written, unknown for relocations read from an object file.
+    pub location: Option<SourceLocation>,
+}
 
 pub struct ByteCodeCompiler {
     pub lines: Vec<Line>,
     pub labels: HashMap<String, usize>,
+    pub labelLocations: HashMap<String, SourceLocation>,
+    pub sourceMap: Vec<Option<SourceLocation>>,
+    pub relocations: Vec<Relocation>,
+    /// Address counter of the code section during layout. Data will get its own.
     pub instructionIndex: usize,
+    pub warnings: Vec<String>,
+    /// Entry label chosen on the command line, overrides `#entry`.
+    pub entryLabel: Option<String>,
+    /// Build without an entry point when none was asked for.
+    pub library: bool,
+    /// Build a relocatable object: undefined labels become imports resolved by the linker.
+    pub object: bool,
+    pub imports: Vec<String>,
+    /// Labels named by `#export`. Only these, and the entry label, are visible
+    /// outside an object file; every other label stays local to it.
+    pub exports: Vec<String>,
+    pub entry: Option<usize>,
+    /// Number of words reserved for the stack at the start of memory.
+    pub stackSize: usize,
+    /// Where `call` keeps return addresses for `ret`.
+    pub callMode: CallMode,
+    /// Keep freed heap blocks out of reuse and fault on double free and use after free.
+    pub checkedHeap: bool,
+    /// Addresses count bytes instead of words. `load` and `store` then move a
+    /// 64-bit word at a byte address and `sp` steps 8 bytes per push.
+    pub byteAddressing: bool,
+    pub limits: Limits,
+    /// Modules loaded at runtime, by path, with their relocated symbols.
+    pub modules: HashMap<String, HashMap<String, usize>>,
+    /// Paths of `modules` in the order they were loaded, which fixes their addresses.
+    pub moduleOrder: Vec<String>,
+    intermediateEntry: Option<(String, SourceLocation)>,
 }
 
 impl ByteCodeCompiler {
+    pub fn new() -> ByteCodeCompiler {
+        ByteCodeCompiler {
+            lines: vec![],
+            labels: HashMap::new(),
+            labelLocations: HashMap::new(),
+            sourceMap: vec![],
+            relocations: vec![],
+            instructionIndex: 0,
+            warnings: vec![],
+            entryLabel: None,
+            library: false,
+            object: false,
+            imports: vec![],
+            exports: vec![],
+            entry: None,
+            stackSize: defaultStackSize,
+            callMode: CallMode::Hidden,
+            checkedHeap: false,
+            byteAddressing: false,
+            limits: Limits::default(),
+            modules: HashMap::new(),
+            moduleOrder: vec![],
+            intermediateEntry: None,
+        }
+    }
     pub fn getLineArgCode(arg: &str) -> (u64, bool) {
-        if arg.len() > 0 {
-            if arg.chars().next().unwrap() == 'r' || arg.chars().next().unwrap() == 's' {
-                return (
-                    stringToReg.iter().position(|&s| s == arg).unwrap() as u64,
-                    true,
-                );
+        if !arg.is_empty() {
+            if let Some(register) = stringToReg.iter().position(|&s| s == arg) {
+                return (register as u64, true);
             } else {
-                if arg.trim().len() > 1 {
-                    //println!("{}", arg);
-                    if arg.starts_with("0x") {
-                        return match u64::from_str_radix(arg.trim_start_matches("0x"), 16) {
-                            Ok(v) => (v, false),
-                            Err(_) => {
-                                (0, false)
-                            }
-                        };
-                    }
+                //println!("{}", arg);
+                if arg.trim().len() > 1 && arg.starts_with("0x") {
+                    return match u64::from_str_radix(arg.trim_start_matches("0x"), 16) {
+                        Ok(v) => (v, false),
+                        Err(_) => {
+                            (0, false)
+                        }
+                    };
                 }
-                if arg.trim().len() > 2 {
-                    if arg.starts_with("\"") && arg.ends_with("\"") {
-                        let value = arg.trim_start_matches("\"").trim_end_matches("\"");
-                        return (value.chars().next().unwrap() as u64, false);
-                    }
+                if arg.trim().len() > 2 && arg.starts_with("\"") && arg.ends_with("\"") {
+                    let value = arg.trim_start_matches("\"").trim_end_matches("\"");
+                    return (value.chars().next().unwrap() as u64, false);
+                }
+                if let Some(value) = parseFloat(arg) {
+                    return (value.to_bits(), false);
+                }
+                if let Some(negative) = arg.strip_prefix('-') {
+                    // Negative literals are stored as their two's complement.
+                    return match parseNumber(negative) {
+                        Some(v) => (v.wrapping_neg(), false),
+                        None => (0, false),
+                    };
                 }
                 return match arg.parse::<u64>() {
                     Ok(v) => (v, false),
@@ -45,432 +211,1740 @@ impl ByteCodeCompiler {
                 };
             }
         }
-        return (0, false);
+        (0, false)
     }
 
-    pub fn compileByteCodeFromIntermediate(&mut self, intermediateCode:IntermediateLanguage) {
-        for i in intermediateCode.lines.iter(){
+    pub fn compileByteCodeFromIntermediate(&mut self, intermediateCode:IntermediateLanguage) -> Result<(), String> {
+        self.layout(&intermediateCode)?;
+        self.emit(&intermediateCode)?;
+        self.lint(&intermediateCode);
+        self.relocate()?;
+        if self.object {
+            // The linker picks the entry point once all objects are combined.
+            self.entryLabel = self.entryLabel.take().or(self.intermediateEntry.take().map(|(label, _)| label));
+        } else if let Some(label) = self.entryLabel.clone() {
+            self.entry = Some(self.resolveEntry(&label, "selected with --entry")?);
+        } else if let Some((label, location)) = self.intermediateEntry.take() {
+            self.entry = Some(self.resolveEntry(&label, &format!("selected by #entry at {}", location))?);
+        } else if !self.library {
+            self.entry = Some(self.resolveEntry("main", "the default entry point")?);
+        }
+        if !self.object
+            && let Err(errors) = self.verify()
+        {
+            // The passes above catch almost everything; what is left are numeric
+            // jump targets outside the program.
+            let error = &errors[0];
+            return Err(match error.index.and_then(|index| self.sourceMap[index].as_ref()) {
+                Some(location) => format!("{}: {}", location, error.message),
+                None => error.to_string(),
+            });
+        }
+        if cfg!(debug_assertions) {
+            eprintln!("{:?}", self.lines);
+        }
+        Ok(())
+    }
+
+    /// First pass: assigns every instruction its address in the code section
+    /// and every label the address of the instruction that follows it.
+    fn layout(&mut self, intermediateCode: &IntermediateLanguage) -> Result<(), String> {
+        self.intermediateEntry = intermediateCode.entry.clone();
+        for i in intermediateCode.lines.iter() {
             match i {
-                IntermediateLanguageLine::Instruction(line) => {
-                    self.instructionIndex += 1
-                },
+                IntermediateLanguageLine::Instruction(_) => self.instructionIndex += 1,
                 IntermediateLanguageLine::Label(label) => {
                     let name = label.label.trim_end_matches(":");
-                    self.labels
-                        .insert(name.to_string(), self.instructionIndex +1);
+                    if let Some(previous) = self.labelLocations.get(name) {
+                        return Err(format!(
+                            "{}: duplicate label `{}`, first defined at {}",
+                            label.location, name, previous
+                        ));
+                    }
+                    self.labelLocations.insert(name.to_string(), label.location.clone());
+                    self.labels.insert(name.to_string(), self.instructionIndex);
                 }
             }
         }
-        for i in intermediateCode.lines{
+        for (name, location) in &intermediateCode.exports {
+            if !self.labels.contains_key(name) {
+                return Err(format!("{}: exported label `{}` is not defined", location, name));
+            }
+            if !self.exports.contains(name) {
+                self.exports.push(name.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns about labels nobody jumps to and code nothing can reach.
+    fn lint(&mut self, intermediateCode: &IntermediateLanguage) {
+        let mut referenced: HashSet<String> = HashSet::new();
+        let entryLabel = self
+            .entryLabel
+            .clone()
+            .or_else(|| self.intermediateEntry.as_ref().map(|(label, _)| label.clone()))
+            .unwrap_or_else(|| "main".to_string());
+        referenced.insert(entryLabel);
+        referenced.extend(self.exports.iter().cloned());
+        // Every label an operand names, memory operands included, has a relocation.
+        referenced.extend(self.relocations.iter().map(|relocation| relocation.symbol.clone()));
+        let mut unreachableFrom: Option<&str> = None;
+        for i in intermediateCode.lines.iter() {
             match i {
                 IntermediateLanguageLine::Instruction(line) => {
-                    if line.instruction == "call"
-                        || line.instruction == "jmp"
-                        || line.instruction == "jz"
-                        || line.instruction == "jnz"
-                    {
-                        if cfg!(debug_assertions) {
-                            println!(
-                                "{}:{}",
-                                line.arg1,
-                                self.labels[&line.arg1.to_string()]
-                            );
-                        }
-
-                        let (arg1, reg1) = (self.labels[&line.arg1.to_string()], false);
-                        let (arg2, reg2) = ByteCodeCompiler::getLineArgCode(line.arg2.as_str());
-                        self.lines.push(Line {
-                            instruction: stringInstructionsToU8
-                                .iter()
-                                .position(|&s| s == line.instruction)
-                                .unwrap() as u8,
-                            arg1: arg1 as u64,
-                            arg2: arg2,
-                            arg1IsReg: reg1,
-                            arg2IsReg: reg2,
-                        });
-                        continue;
+                    if let Some(terminator) = unreachableFrom.take() {
+                        self.warnings.push(format!(
+                            "{}: unreachable code after `{}`",
+                            line.location, terminator
+                        ));
                     }
-                    if cfg!(debug_assertions) {
-                        println!("{:?}", line);
+                    if byMnemonic(&line.instruction).is_some_and(|spec| spec.endsBlock) {
+                        unreachableFrom = Some(line.instruction.as_str());
                     }
-                    let (arg1, reg1) = ByteCodeCompiler::getLineArgCode(line.arg1.as_str());
-                    let (arg2, reg2) = ByteCodeCompiler::getLineArgCode(line.arg2.as_str());
-                    self.lines.push(Line {
-                        instruction: stringInstructionsToU8
-                            .iter()
-                            .position(|&s| s == line.instruction)
-                            .expect(&("invalid instruction: ".to_string() + line.instruction.as_str()))
-                            as u8,
-                        arg1: arg1,
-                        arg2: arg2,
-                        arg1IsReg: reg1,
-                        arg2IsReg: reg2,
-                    });
-                },
-                IntermediateLanguageLine::Label(label) => {
-
                 }
+                IntermediateLanguageLine::Label(_) => unreachableFrom = None,
             }
         }
-        let main_index = self.labels["main"];
-
-        let jmp_main = Line {
-            instruction: stringInstructionsToU8
-                .iter()
-                .position(|&s| s == "jmp")
-                .unwrap() as u8,
-            arg1: main_index as u64,
-            arg1IsReg: false,
-            arg2: 0,
-            arg2IsReg: false,
-        };
+        let mut unused: Vec<(&String, &SourceLocation)> = self
+            .labelLocations
+            .iter()
+            .filter(|(name, _)| !referenced.contains(name.as_str()))
+            .collect();
+        unused.sort_by_key(|(_, location)| (location.file.clone(), location.line));
+        for (name, location) in unused {
+            self.warnings.push(format!("{}: label `{}` is never used", location, name));
+        }
+    }
 
-        self.lines.insert(0, jmp_main);
-        if cfg!(debug_assertions) {
-            println!("{:?}", self.lines);
-        }
-    }
-    pub fn run(&mut self) {
-        let mut registers = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let mut mem: Vec<u64> = vec![];
-        let mut carrierbit = false;
-        let mut callStack: Vec<usize> = Vec::new();
-        let mut ip = 0;
-        while ip < self.lines.len() {
-            let line = &self.lines[ip];
-            match line.instruction {
-                1 => registers[line.arg1 as usize] = registers[line.arg2 as usize],
-                2 => {
-                    if line.arg2IsReg {
-                        registers[line.arg1 as usize] += registers[line.arg2 as usize]
-                    } else {
-                        registers[line.arg1 as usize] += line.arg2
+    /// Second pass: encodes every instruction at its address. Operands naming a
+    /// label are emitted as 0 with a relocation pointing back at the label.
+    fn emit(&mut self, intermediateCode: &IntermediateLanguage) -> Result<(), String> {
+        for i in &intermediateCode.lines {
+            let IntermediateLanguageLine::Instruction(line) = i else {
+                continue;
+            };
+            if cfg!(debug_assertions) {
+                eprintln!("{:?}", line);
+            }
+            let index = self.lines.len();
+            let mut addressing = Addressing::default();
+            let spec = byMnemonic(&line.instruction).unwrap_or_else(|| panic!("invalid instruction: {}", line.instruction));
+            let kinds = spec.operands;
+            let mut encoded = [(0u64, false); 2];
+            let operands = [(&line.arg1, kinds.0, Operand::Arg1), (&line.arg2, kinds.1, Operand::Arg2)];
+            for (position, (text, kind, operand)) in operands.into_iter().enumerate() {
+                let ordinal = if position == 0 { "first" } else { "second" };
+                if kind == OperandKind::None {
+                    if !text.is_empty() {
+                        return Err(format!("{}: `{}` takes no {} operand, found `{}`", line.location, line.instruction, ordinal, text));
                     }
+                    continue;
                 }
-                3 => {
-                    if line.arg2IsReg {
-                        registers[line.arg1 as usize] -= registers[line.arg2 as usize]
-                    } else {
-                        registers[line.arg1 as usize] -= line.arg2
-                    }
+                if text.is_empty() {
+                    return Err(format!("{}: `{}` is missing its {} operand", line.location, line.instruction, ordinal));
                 }
-                4 => {
-                    if line.arg2IsReg {
-                        registers[line.arg1 as usize] /= registers[line.arg2 as usize]
-                    } else {
-                        registers[line.arg1 as usize] /= line.arg2
+                if text.starts_with('[') {
+                    if kind != OperandKind::Address {
+                        return Err(format!("{}: `{}` does not take a memory operand as its {} operand", line.location, line.instruction, ordinal));
                     }
+                    let memory = self.memoryOperand(text, index, operand, &line.location)?;
+                    addressing = memory.addressing;
+                    encoded[position] = (memory.displacement, false);
+                    continue;
                 }
-                5 => {
-                    if line.arg2IsReg {
-                        registers[line.arg1 as usize] *= registers[line.arg2 as usize]
-                    } else {
-                        registers[line.arg1 as usize] *= line.arg2
-                    }
+                let (value, isReg) = self.operandCode(text, index, operand, &line.location)?;
+                if kind == OperandKind::Register && !isReg {
+                    return Err(format!("{}: `{}` needs a register as its {} operand, found `{}`", line.location, line.instruction, ordinal, text));
                 }
-                6 => {
-                    let dest = line.arg1;
-                    let rhs = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    registers[dest as usize] &= rhs;
-                }
-                7 => {
-                    let dest = line.arg1;
-                    let rhs = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    registers[dest as usize] |= rhs;
-                }
-                8 => {
-                    let dest = line.arg1;
-                    let rhs = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    registers[dest as usize] ^= rhs;
+                if kind == OperandKind::Register && value == ipRegister as u64 {
+                    return Err(format!("{}: `ip` is read only, `{}` cannot write it", line.location, line.instruction));
                 }
-                9 => {
-                    if line.arg2IsReg {
-                        registers[line.arg1 as usize] >>= registers[line.arg2 as usize];
-                    } else {
-                        registers[line.arg1 as usize] >>= line.arg2;
-                    }
+                encoded[position] = (value, isReg);
+            }
+            let [(arg1, reg1), (arg2, reg2)] = encoded;
+            self.sourceMap.push(Some(line.location.clone()));
+            self.lines.push(Line {
+                instruction: spec.opcode,
+                arg1,
+                arg2,
+                arg1IsReg: reg1,
+                arg2IsReg: reg2,
+                addressing,
+            });
+        }
+        debug_assert_eq!(self.lines.len(), self.instructionIndex);
+        Ok(())
+    }
+
+    /// Encodes one operand: a register, a label (through a relocation, so it may
+    /// be defined later or imported), a number or a quoted character.
+    fn operandCode(&mut self, arg: &str, index: usize, operand: Operand, location: &SourceLocation) -> Result<(u64, bool), String> {
+        if stringToReg.contains(&arg) {
+            return Ok(ByteCodeCompiler::getLineArgCode(arg));
+        }
+        if arg.starts_with(|c: char| c.is_alphabetic() || c == '_' || c == '.') {
+            self.relocations.push(Relocation {
+                index,
+                operand,
+                symbol: arg.to_string(),
+                addend: 0,
+                location: Some(location.clone()),
+            });
+            return Ok((0, false));
+        }
+        let isNumber = parseNumber(arg.strip_prefix('-').unwrap_or(arg)).is_some() || parseFloat(arg).is_some();
+        let isCharacter = arg.len() > 2 && arg.starts_with('"') && arg.ends_with('"');
+        if !isNumber && !isCharacter {
+            return Err(format!("{}: invalid operand `{}`", location, arg));
+        }
+        Ok(ByteCodeCompiler::getLineArgCode(arg))
+    }
+
+    /// Parses a memory operand `[base+index*scale+disp]`. Each part is optional:
+    /// the first plain register is the base, a second one or one with `*scale`
+    /// is the index, numbers add up to the displacement, and one label may be
+    /// added to it through a relocation.
+    fn memoryOperand(&mut self, arg: &str, index: usize, operand: Operand, location: &SourceLocation) -> Result<MemoryOperand, String> {
+        let invalid = |reason: &str| format!("{}: invalid memory operand `{}`: {}", location, arg, reason);
+        let Some(inner) = arg.strip_prefix('[').and_then(|inner| inner.strip_suffix(']')) else {
+            return Err(invalid("missing `]`"));
+        };
+        let mut addressing = Addressing {
+            arg1IsMem: operand == Operand::Arg1,
+            arg2IsMem: operand == Operand::Arg2,
+            scale: 1,
+            ..Addressing::default()
+        };
+        let mut displacement: u64 = 0;
+        let mut label: Option<&str> = None;
+        let mut terms: Vec<(bool, String)> = vec![];
+        let mut negative = false;
+        let mut current = String::new();
+        for character in inner.chars() {
+            if character == '+' || character == '-' {
+                if !current.is_empty() {
+                    terms.push((negative, std::mem::take(&mut current)));
+                } else if !terms.is_empty() {
+                    return Err(invalid("empty term"));
                 }
-                10 => {
-                    if line.arg2IsReg {
-                        registers[line.arg1 as usize] <<= registers[line.arg2 as usize];
-                    } else {
-                        registers[line.arg1 as usize] <<= line.arg2;
-                    }
+                negative = character == '-';
+            } else {
+                current.push(character);
+            }
+        }
+        if current.is_empty() {
+            return Err(invalid("empty term"));
+        }
+        terms.push((negative, current));
+        for (negative, term) in &terms {
+            let (negative, term) = (*negative, term.as_str());
+            if let Some((register, scale)) = term.split_once('*') {
+                let Some(register) = stringToReg.iter().position(|&r| r == register) else {
+                    return Err(invalid("only a register can be scaled"));
+                };
+                if negative || addressing.index.is_some() || !matches!(scale, "1" | "2" | "4" | "8") {
+                    return Err(invalid("the index must be one added register scaled by 1, 2, 4 or 8"));
                 }
-                11 => {
-                    let address = if line.arg1IsReg {
-                        registers[line.arg1 as usize]
-                    } else {
-                        line.arg1
-                    };
-                    let value = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    mem[address as usize] = value;
+                addressing.index = Some(register as u8);
+                addressing.scale = scale.parse().unwrap();
+            } else if let Some(register) = stringToReg.iter().position(|&r| r == term) {
+                if negative {
+                    return Err(invalid("registers cannot be subtracted"));
                 }
-                12 => {
-                    let address = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    registers[line.arg1 as usize] = mem[address as usize];
-                }
-                13 => {
-                    //println!("rv{}", registers[9]);
-                    let value = if line.arg1IsReg {
-                        registers[line.arg1 as usize]
-                    } else {
-                        line.arg1
-                    };
-                    registers[9] += 1;
-                    mem[registers[9] as usize] = value;
+                if addressing.base.is_none() {
+                    addressing.base = Some(register as u8);
+                } else if addressing.index.is_none() {
+                    addressing.index = Some(register as u8);
+                } else {
+                    return Err(invalid("at most two registers"));
                 }
-                14 => {
-                    registers[line.arg1 as usize] = mem[registers[9] as usize];
-                    registers[9] -= 1;
+            } else if let Some(value) = parseNumber(term) {
+                displacement = if negative {
+                    displacement.wrapping_sub(value)
+                } else {
+                    displacement.wrapping_add(value)
+                };
+            } else {
+                if negative || label.is_some() {
+                    return Err(invalid("at most one added label"));
                 }
-                15 => {
-                    if cfg!(debug_assertions) {
-                        println!("jmping to {}", line.arg1);
-                    }
-                    if line.arg1IsReg {
-                        ip = registers[line.arg1 as usize] as usize;
-                    } else {
-                        ip = line.arg1 as usize;
+                label = Some(term);
+            }
+        }
+        if let Some(label) = label {
+            self.relocations.push(Relocation {
+                index,
+                operand,
+                symbol: label.to_string(),
+                addend: displacement,
+                location: Some(location.clone()),
+            });
+        }
+        Ok(MemoryOperand { addressing, displacement })
+    }
+
+    /// Third pass: patches every relocated operand with its label's address.
+    /// Objects keep the relocations so the linker can patch them again.
+    fn relocate(&mut self) -> Result<(), String> {
+        for relocation in &self.relocations {
+            let Some(&target) = self.labels.get(&relocation.symbol) else {
+                if self.object {
+                    if !self.imports.contains(&relocation.symbol) {
+                        self.imports.push(relocation.symbol.clone());
                     }
                     continue;
                 }
-                16 => {
-                    if cfg!(debug_assertions) {
-                        println!("jz:{}", carrierbit);
-                    }
-                    if carrierbit {
-                        if line.arg1IsReg {
-                            ip = registers[line.arg1 as usize] as usize;
-                        } else {
-                            ip = line.arg1 as usize;
-                        }
-                        continue;
-                    }
+                return Err(format!(
+                    "{}: undefined label `{}`",
+                    relocation.location.as_ref().unwrap(),
+                    relocation.symbol
+                ));
+            };
+            if cfg!(debug_assertions) {
+                eprintln!("{}:{}", relocation.symbol, target);
+            }
+            relocation.apply(&mut self.lines[relocation.index], target);
+        }
+        Ok(())
+    }
+
+    fn resolveEntry(&self, label: &str, origin: &str) -> Result<usize, String> {
+        self.labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("entry label `{}` ({}) is not defined", label, origin))
+    }
+    /// Sets up a machine at the program's entry point. The first `stackSize + 1`
+    /// words of memory are the stack: `sp` starts at 0, which is never written,
+    /// and the stack grows up to `stackSize`. Heap allocations come after it.
+    pub fn start(&self) -> Result<Machine, VmFault> {
+        self.verify().map_err(|errors| VmFault::InvalidBytecode { errors })?;
+        let wordSize = if self.byteAddressing { 8 } else { 1 };
+        Ok(Machine {
+            registers: [0; registerCount],
+            mem: vec![0; self.stackSize + 1],
+            carrierbit: false,
+            flags: Flags::default(),
+            callStack: Vec::new(),
+            heap: Heap::new(self.checkedHeap, wordSize, self.limits.maxHeapWords),
+            ip: self.entry.expect("no entry point"),
+            executed: 0,
+            callDepth: 0,
+            console: Console::default(),
+            exitCode: None,
+            programHash: programHash(self),
+            started: Instant::now(),
+        })
+    }
+
+    /// Whether `machine` has more to run: it hasn't exited and `ip` is inside the program.
+    pub fn isRunning(&self, machine: &Machine) -> bool {
+        machine.exitCode.is_none() && machine.ip < self.lines.len()
+    }
+
+    /// Runs until the program ends, exits or faults.
+    pub fn resume(&mut self, machine: &mut Machine) -> Result<(), VmFault> {
+        while self.isRunning(machine) {
+            self.step(machine)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the program from its entry point, exiting the process with the
+    /// program's code when it exits through interrupt 0 or `hlt`.
+    pub fn run(&mut self) -> Result<(), VmFault> {
+        let mut machine = self.start()?;
+        self.resume(&mut machine)?;
+        if let Some(code) = machine.exitCode {
+            process::exit(code);
+        }
+        Ok(())
+    }
+
+    /// Runs the instruction at `machine.ip`, after checking the limits. On a
+    /// fault `ip` still points at the faulting instruction.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<(), VmFault> {
+        let ip = machine.ip;
+        if let Some(limit) = self.limits.maxInstructions
+            && machine.executed >= limit
+        {
+            return Err(VmFault::InstructionLimit { ip, limit });
+        }
+        if let Some(limit) = self.limits.timeout
+            && machine.executed.is_multiple_of(timeoutCheckInterval)
+            && machine.started.elapsed() >= limit
+        {
+            return Err(VmFault::Timeout { ip, limit });
+        }
+        machine.registers[ipRegister] = ip as u64;
+        machine.ip = self.execute(machine, ip)?;
+        machine.executed += 1;
+        Ok(())
+    }
+
+    /// Words of `mem` the next instruction may write, for history to save
+    /// first. `None` when it's a heap interrupt, which may move or resize
+    /// anything after the stack.
+    pub fn writtenWords(&self, machine: &Machine) -> Option<Vec<usize>> {
+        let line = &self.lines[machine.ip];
+        let registers = &machine.registers;
+        let wordSize = machine.heap.wordSize();
+        let pushed = || vec![(registers[spRegister] / wordSize + 1) as usize];
+        Some(match line.instruction {
+            op::store | op::store8 | op::store16 | op::store32 | op::store64 => {
+                self.accessedWords(line, line.arg1, line.arg1IsReg, line.addressing.arg1IsMem, registers)
+            }
+            op::push | op::enter => pushed(),
+            op::call if self.callMode == CallMode::Stack => pushed(),
+            op::int if matches!(registers[interruptRegister], 1 | 7 | 8) => return None,
+            _ => vec![],
+        })
+    }
+
+    /// Words of `mem` the next instruction reads through a load or off the stack.
+    pub fn readWords(&self, machine: &Machine) -> Vec<usize> {
+        let line = &self.lines[machine.ip];
+        let registers = &machine.registers;
+        let wordSize = machine.heap.wordSize();
+        let popped = |sp: u64| vec![(sp / wordSize) as usize];
+        match line.instruction {
+            op::load | op::load8 | op::load16 | op::load32 | op::load64 => {
+                self.accessedWords(line, line.arg2, line.arg2IsReg, line.addressing.arg2IsMem, registers)
+            }
+            op::pop => popped(registers[spRegister]),
+            op::leave => popped(registers[fpRegister]),
+            op::ret if self.callMode == CallMode::Stack => popped(registers[spRegister]),
+            _ => vec![],
+        }
+    }
+
+    /// Words covered by a load or store through the given address operand.
+    fn accessedWords(&self, line: &Line, operand: u64, isReg: bool, isMem: bool, registers: &[u64]) -> Vec<usize> {
+        let address = if isMem {
+            effectiveAddress(operand, &line.addressing, registers)
+        } else if isReg {
+            registers[operand as usize]
+        } else {
+            operand
+        };
+        match accessWidth(line.instruction, self.byteAddressing) {
+            Some(width) => (address / 8..=address.saturating_add(width - 1) / 8).map(|word| word as usize).collect(),
+            None => vec![address as usize],
+        }
+    }
+
+    /// Executes the instruction at `ip` and returns the address of the next one.
+    fn execute(&mut self, machine: &mut Machine, ip: usize) -> Result<usize, VmFault> {
+        let line = self.lines[ip].clone();
+        let wordSize = machine.heap.wordSize();
+        let stack = StackRegion { size: self.stackSize, wordSize };
+        let Machine { registers, mem, carrierbit, flags, callStack, heap, callDepth, .. } = machine;
+        match line.instruction {
+            op::mov | op::set => {
+                registers[line.arg1 as usize] = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
                 }
-                17 => {
-                    if cfg!(debug_assertions) {
-                        println!("jnz:{}", carrierbit);
-                    }
-                    if !carrierbit {
-                        if line.arg1IsReg {
-                            ip = registers[line.arg1 as usize] as usize;
-                        } else {
-                            ip = line.arg1 as usize;
-                        }
-                        continue;
-                    }
+            }
+            op::add
+            | op::sub
+            | op::div
+            | op::mul
+            | op::and
+            | op::or
+            | op::xor
+            | op::shr
+            | op::shl
+            | op::divs
+            | op::mods
+            | op::sar
+            | op::r#mod
+            | op::not
+            | op::neg
+            | op::inc
+            | op::dec
+            | op::rol
+            | op::ror
+            | op::min
+            | op::max => {
+                let rhs = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                if rhs == 0 && matches!(line.instruction, op::div | op::divs | op::mods | op::r#mod) {
+                    return Err(VmFault::DivideByZero { ip });
                 }
-                18 => {
-                    let val1 = if line.arg1IsReg {
-                        registers[line.arg1 as usize]
-                    } else {
-                        line.arg1
-                    };
-                    let val2 = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    carrierbit = val1 == val2
+                let (result, carry, overflow) = alu(line.instruction, registers[line.arg1 as usize], rhs);
+                registers[line.arg1 as usize] = result;
+                flags.set(result, carry, overflow);
+            }
+            op::store | op::store8 | op::store16 | op::store32 | op::store64 => {
+                let address = if line.addressing.arg1IsMem {
+                    effectiveAddress(line.arg1, &line.addressing, registers)
+                } else if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let value = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                let width = accessWidth(line.instruction, self.byteAddressing);
+                heap.checkAccess(if width.is_some() { address / 8 } else { address }, address, ip)?;
+                let size = mem.len();
+                let stored = match width {
+                    Some(width) => storeBytes(mem, address, width, value),
+                    None => mem.get_mut(address as usize).map(|slot| *slot = value),
+                };
+                if stored.is_none() {
+                    return Err(VmFault::MemoryOutOfBounds { ip, address, size });
                 }
-                19 => {
-                    let val1 = if line.arg1IsReg {
-                        registers[line.arg1 as usize]
-                    } else {
-                        line.arg1
-                    };
-                    let val2 = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    carrierbit = val1 != val2
+            }
+            op::load | op::load8 | op::load16 | op::load32 | op::load64 => {
+                let address = if line.addressing.arg2IsMem {
+                    effectiveAddress(line.arg2, &line.addressing, registers)
+                } else if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                let width = accessWidth(line.instruction, self.byteAddressing);
+                heap.checkAccess(if width.is_some() { address / 8 } else { address }, address, ip)?;
+                let value = match width {
+                    Some(width) => loadBytes(mem, address, width),
+                    None => mem.get(address as usize).copied(),
+                };
+                let Some(value) = value else {
+                    return Err(VmFault::MemoryOutOfBounds { ip, address, size: mem.len() });
+                };
+                registers[line.arg1 as usize] = value;
+            }
+            op::push => {
+                let value = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                push(mem, registers, stack, ip, value)?;
+            }
+            op::pop => {
+                registers[line.arg1 as usize] = pop(mem, registers, stack, ip)?;
+            }
+            op::jmp => {
+                if cfg!(debug_assertions) {
+                    eprintln!("jmping to {}", line.arg1);
                 }
-                20 => {
-                    let val1 = if line.arg1IsReg {
-                        registers[line.arg1 as usize]
-                    } else {
-                        line.arg1
-                    };
-                    let val2 = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    carrierbit = val1 > val2
+                return Ok(jumpTarget(&line, registers));
+            }
+            op::jz => {
+                if cfg!(debug_assertions) {
+                    eprintln!("jz:{}", carrierbit);
                 }
-                21 => {
-                    let val1 = if line.arg1IsReg {
-                        registers[line.arg1 as usize]
-                    } else {
-                        line.arg1
-                    };
-                    let val2 = if line.arg2IsReg {
-                        registers[line.arg2 as usize]
-                    } else {
-                        line.arg2
-                    };
-                    carrierbit = val1 < val2
-                }
-                22 => {
-                    let mut p: u64 = 0;
-                    let mut useP: bool = false;
-                    interrupt(0, 0, &mut mem, &mut p, &mut useP);
-                }
-                23 => {
-                    let mut p: u64 = 0;
-                    let mut useP: bool = false;
-                    if line.arg1IsReg {
-                        //println!("{}",regristers[7]);
-                        interrupt(
-                            registers[8] as u8,
-                            registers[line.arg1 as usize],
-                            &mut mem,
-                            &mut p,
-                            &mut useP,
-                        )
-                    } else {
-                        interrupt(registers[8] as u8, line.arg1, &mut mem, &mut p, &mut useP)
-                    }
-                    if useP {
-                        registers[7] = p
-                    }
+                if *carrierbit {
+                    return Ok(jumpTarget(&line, registers));
+                }
+            }
+            op::jnz => {
+                if cfg!(debug_assertions) {
+                    eprintln!("jnz:{}", carrierbit);
+                }
+                if !*carrierbit {
+                    return Ok(jumpTarget(&line, registers));
+                }
+            }
+            op::eq => {
+                let val1 = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let val2 = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                *carrierbit = val1 == val2
+            }
+            op::neq => {
+                let val1 = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let val2 = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                *carrierbit = val1 != val2
+            }
+            op::big => {
+                let val1 = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let val2 = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                *carrierbit = val1 > val2
+            }
+            op::sm => {
+                let val1 = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let val2 = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                *carrierbit = val1 < val2
+            }
+            op::hlt => interrupt(0, 0, machine)?,
+            op::int => {
+                let value = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let intCode = registers[interruptRegister];
+                if intCode == loadModuleInterrupt as u64 {
+                    registers[resultRegister] = self.loadModule(mem, value, self.byteAddressing).unwrap_or_else(|error| {
+                        eprintln!("error: {}", error);
+                        loadModuleFailed
+                    });
+                } else {
+                    interrupt(intCode, value, machine)?
+                }
+            }
+            op::call => {
+                if let Some(limit) = self.limits.maxCallDepth
+                    && *callDepth >= limit
+                {
+                    return Err(VmFault::CallDepthLimit { ip, limit });
                 }
-                24 => registers[line.arg1 as usize] = line.arg2,
-                25 => {
-                    //println!("call called",);
-                    callStack.push(ip + 1);
-                    if line.arg1IsReg {
-                        ip = registers[line.arg1 as usize] as usize;
-                    } else {
-                        ip = line.arg1 as usize;
+                *callDepth += 1;
+                registers[lrRegister] = ip as u64 + 1;
+                match self.callMode {
+                    CallMode::Hidden => callStack.push(ip + 1),
+                    CallMode::Stack => push(mem, registers, stack, ip, ip as u64 + 1)?,
+                    CallMode::Link => {}
+                }
+                let target = jumpTarget(&line, registers);
+                if cfg!(debug_assertions) {
+                    eprintln!("call going to {}", target);
+                }
+                return Ok(target);
+            }
+            op::ret => {
+                *callDepth = callDepth.saturating_sub(1);
+                match self.callMode {
+                    CallMode::Hidden => {}
+                    CallMode::Stack => {
+                        if registers[spRegister] == 0 {
+                            return Err(VmFault::ReturnWithoutCall { ip });
+                        }
+                        return Ok(pop(mem, registers, stack, ip)? as usize);
                     }
-                    if cfg!(debug_assertions) {
-                        println!("call going to {}", ip);
+                    CallMode::Link => {
+                        return Ok(registers[lrRegister] as usize);
                     }
-                    continue;
                 }
-                26 => {
-                    //println!("{:?}", callStack);
-                    if let Some(return_address) = callStack.pop() {
-                        ip = return_address;
-                        continue;
-                    } else {
-                        panic!("RET with empty call stack. ip: {}", ip);
-                    }
-                    //println!("returning to {}",ip);
-                    continue;
+                return callStack.pop().ok_or(VmFault::ReturnWithoutCall { ip });
+            }
+            op::bigs | op::sms => {
+                let val1 = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                } as i64;
+                let val2 = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                } as i64;
+                *carrierbit = if line.instruction == op::bigs { val1 > val2 } else { val1 < val2 }
+            }
+            op::fadd | op::fsub | op::fmul | op::fdiv => {
+                let rhs = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                let (lhs, rhs) = (f64::from_bits(registers[line.arg1 as usize]), f64::from_bits(rhs));
+                let result = match line.instruction {
+                    op::fadd => lhs + rhs,
+                    op::fsub => lhs - rhs,
+                    op::fmul => lhs * rhs,
+                    _ => lhs / rhs,
+                };
+                registers[line.arg1 as usize] = result.to_bits();
+            }
+            op::fsqrt => {
+                registers[line.arg1 as usize] = f64::from_bits(registers[line.arg1 as usize]).sqrt().to_bits();
+            }
+            op::feq | op::fneq | op::fbig | op::fsm => {
+                let val1 = f64::from_bits(if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                });
+                let val2 = f64::from_bits(if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                });
+                *carrierbit = match line.instruction {
+                    op::feq => val1 == val2,
+                    op::fneq => val1 != val2,
+                    op::fbig => val1 > val2,
+                    _ => val1 < val2,
+                };
+            }
+            op::itof => {
+                registers[line.arg1 as usize] = (registers[line.arg1 as usize] as i64 as f64).to_bits();
+            }
+            op::ftoi => {
+                // Rounds toward zero, saturating at the i64 range; NaN becomes 0.
+                registers[line.arg1 as usize] = f64::from_bits(registers[line.arg1 as usize]) as i64 as u64;
+            }
+            op::cmp => {
+                let val1 = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let val2 = if line.arg2IsReg {
+                    registers[line.arg2 as usize]
+                } else {
+                    line.arg2
+                };
+                let (result, carry, overflow) = alu(op::sub, val1, val2);
+                flags.set(result, carry, overflow);
+            }
+            op::je
+            | op::jne
+            | op::jl
+            | op::jg
+            | op::jle
+            | op::jge
+            | op::jb
+            | op::ja
+            | op::jc
+            | op::jnc
+            | op::jo
+            | op::jno
+            | op::js
+            | op::jns => {
+                if flags.condition(line.instruction) {
+                    return Ok(jumpTarget(&line, registers));
                 }
-                _ => panic!(
-                    "invalid instruction {}",
-                    stringInstructionsToU8[line.instruction as usize]
-                ),
             }
-            ip += 1;
+            op::enter => {
+                let locals = if line.arg1IsReg {
+                    registers[line.arg1 as usize]
+                } else {
+                    line.arg1
+                };
+                let framePointer = registers[fpRegister];
+                push(mem, registers, stack, ip, framePointer)?;
+                registers[fpRegister] = registers[spRegister];
+                if locals > (stack.end() - registers[spRegister]) / wordSize {
+                    return Err(VmFault::StackOverflow { ip, stackSize: self.stackSize });
+                }
+                registers[spRegister] += locals * wordSize;
+            }
+            op::leave => {
+                registers[spRegister] = registers[fpRegister];
+                registers[fpRegister] = pop(mem, registers, stack, ip)?;
+            }
+            _ => panic!("invalid instruction {}", mnemonicOf(line.instruction)),
         }
+        Ok(ip + 1)
     }
     fn write_instructions(&mut self, filename: &str) -> io::Result<()> {
         let mut file = File::create(filename)?;
 
+        file.write_all(byteCodeMagic)?;
+        file.write_all(&[byteCodeVersion])?;
+        file.write_all(&self.entry.map_or(noEntry, |entry| entry as u64).to_le_bytes())?;
+        let symbols = self.exportedSymbols();
+        writeCount(&mut file, symbols.len())?;
+        for (name, address) in symbols {
+            writeString(&mut file, &name)?;
+            writeCount(&mut file, address)?;
+        }
+        writeCount(&mut file, self.relocations.len())?;
+        for relocation in &self.relocations {
+            writeCount(&mut file, relocation.index)?;
+            file.write_all(&[match relocation.operand {
+                Operand::Arg1 => 1,
+                Operand::Arg2 => 2,
+            }])?;
+        }
         for line in &self.lines {
-            // Write opcode
-            file.write_all(&[line.instruction])?;
-
-            // Write arg1 and arg2 as little-endian u64
-            file.write_all(&line.arg1.to_le_bytes())?;
-            file.write_all(&line.arg2.to_le_bytes())?;
-
-            // Pack booleans into a single byte
-            let mut flags: u8 = 0;
-            if line.arg1IsReg {
-                flags |= 1 << 0;
-            }
-            if line.arg2IsReg {
-                flags |= 1 << 1;
-            }
-            file.write_all(&[flags])?;
+            file.write_all(&line.encode())?;
         }
         Ok(())
     }
 
-    fn read_instructions(filename: &str) -> io::Result<Vec<Line>> {
+    fn read_instructions(&mut self, filename: &str) -> io::Result<()> {
         let mut file = BufReader::new(File::open(filename)?);
         let mut instructions = Vec::new();
-        let mut buf = [0u8; 18];
-
-        while file.read_exact(&mut buf).is_ok() {
-            let instruction = buf[0];
-            let arg1 = u64::from_le_bytes(buf[1..9].try_into().unwrap());
-            let arg2 = u64::from_le_bytes(buf[9..17].try_into().unwrap());
-            let flags = buf[17];
-            let arg1_is_reg = flags & 1 != 0;
-            let arg2_is_reg = flags & 2 != 0;
-
-            instructions.push(Line {
-                instruction: instruction,
-                arg1: arg1,
-                arg1IsReg: arg1_is_reg,
-                arg2: arg2,
-                arg2IsReg: arg2_is_reg,
-            });
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != byteCodeMagic {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a NIS bytecode file"));
+        }
+        let mut version = [0u8; 1];
+        file.read_exact(&mut version)?;
+        if version[0] != byteCodeVersion {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported bytecode version {}", version[0]),
+            ));
+        }
+        let mut entry = [0u8; 8];
+        file.read_exact(&mut entry)?;
+        let entry = match u64::from_le_bytes(entry) {
+            noEntry => None,
+            entry => Some(entry as usize),
+        };
+        for _ in 0..readCount(&mut file)? {
+            let name = readString(&mut file)?;
+            self.labels.insert(name.clone(), readCount(&mut file)?);
+            self.exports.push(name);
+        }
+        let mut relocations = vec![];
+        for _ in 0..readCount(&mut file)? {
+            let index = readCount(&mut file)?;
+            let operand = match readByte(&mut file)? {
+                1 => Operand::Arg1,
+                2 => Operand::Arg2,
+                other => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid relocation operand {}", other)));
+                }
+            };
+            relocations.push((index, operand));
+        }
+        let mut records = vec![];
+        file.read_to_end(&mut records)?;
+        if records.len() % lineSize != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("file ends in the middle of instruction {}", records.len() / lineSize),
+            ));
+        }
+        for record in records.chunks_exact(lineSize) {
+            instructions.push(Line::decode(record.try_into().unwrap()));
+        }
+        for (index, operand) in relocations {
+            let Some(line) = instructions.get(index) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("relocation of instruction {} outside the code", index),
+                ));
+            };
+            let addend = match operand {
+                Operand::Arg1 => line.arg1,
+                Operand::Arg2 => line.arg2,
+            };
+            self.relocations.push(Relocation { index, operand, symbol: String::new(), addend, location: None });
         }
 
-        Ok(instructions)
+        (self.entry, self.lines) = (entry, instructions);
+        Ok(())
     }
-    fn readFromFile(&mut self, path: &str) {
-        self.lines = ByteCodeCompiler::read_instructions(path).unwrap()
+    pub fn readFromFile(&mut self, path: &str) -> io::Result<()> {
+        self.read_instructions(path)
     }
-    fn writeToFile(&mut self, path: &str) {
+
+    /// The `#export` labels with their addresses, sorted by address.
+    pub fn exportedSymbols(&self) -> Vec<(String, usize)> {
+        let mut symbols: Vec<(String, usize)> = self
+            .exports
+            .iter()
+            .filter_map(|name| self.labels.get(name).map(|&address| (name.clone(), address)))
+            .collect();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        symbols
+    }
+    pub fn writeToFile(&mut self, path: &str) {
         self.write_instructions(path).unwrap()
     }
 }
-fn interrupt(intCode: u8, value: u64, mem: &mut Vec<u64>, p: &mut u64, useP: &mut bool) {
-    match intCode {
-        0 => process::exit(value as i32),
-        1 => {
-            let start = mem.len();
-            mem.resize(start + value as usize, 0);
-            *useP = true;
-            *p = start as u64;
+/// Where a jump, `call` or conditional jump taken goes: its register or immediate operand.
+fn jumpTarget(line: &Line, registers: &[u64]) -> usize {
+    if line.arg1IsReg {
+        registers[line.arg1 as usize] as usize
+    } else {
+        line.arg1 as usize
+    }
+}
+
+/// Address of a memory operand: displacement + base + index * scale, wrapping.
+fn effectiveAddress(displacement: u64, addressing: &Addressing, registers: &[u64]) -> u64 {
+    let base = addressing.base.map_or(0, |base| registers[base as usize]);
+    let index = addressing.index.map_or(0, |index| registers[index as usize]);
+    displacement
+        .wrapping_add(base)
+        .wrapping_add(index.wrapping_mul(addressing.scale as u64))
+}
+
+/// The flags register. Arithmetic and logic instructions and `cmp` update it;
+/// the comparison instructions only set `carrierbit`, which `jz`/`jnz` read.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Flags {
+    pub zero: bool,
+    pub carry: bool,
+    pub overflow: bool,
+    pub negative: bool,
+}
+
+impl Flags {
+    fn set(&mut self, result: u64, carry: bool, overflow: bool) {
+        self.zero = result == 0;
+        self.carry = carry;
+        self.overflow = overflow;
+        self.negative = (result as i64) < 0;
+    }
+
+    /// Whether the conditional jump with this opcode is taken.
+    fn condition(&self, instruction: u8) -> bool {
+        match instruction {
+            op::je => self.zero,
+            op::jne => !self.zero,
+            op::jl => self.negative != self.overflow,
+            op::jg => !self.zero && self.negative == self.overflow,
+            op::jle => self.zero || self.negative != self.overflow,
+            op::jge => self.negative == self.overflow,
+            op::jb => self.carry,
+            op::ja => !self.carry && !self.zero,
+            op::jc => self.carry,
+            op::jnc => !self.carry,
+            op::jo => self.overflow,
+            op::jno => !self.overflow,
+            op::js => self.negative,
+            op::jns => !self.negative,
+            _ => unreachable!("{} is not a conditional jump", instruction),
         }
-        2 => print!("{}", std::char::from_u32(value as u32).unwrap()),
-        3 => print!("{}", value),
-        4 => {
-            *p = mem.len() as u64;
-            *useP = true;
+    }
+}
+
+/// Computes an arithmetic or logic instruction, returning the result with its
+/// carry (unsigned overflow or borrow) and signed overflow. Instructions that
+/// cannot carry or overflow report neither. The caller rules out dividing by zero.
+fn alu(instruction: u8, lhs: u64, rhs: u64) -> (u64, bool, bool) {
+    match instruction {
+        op::add => {
+            let (result, carry) = lhs.overflowing_add(rhs);
+            (result, carry, (lhs as i64).overflowing_add(rhs as i64).1)
+        }
+        op::sub => {
+            let (result, carry) = lhs.overflowing_sub(rhs);
+            (result, carry, (lhs as i64).overflowing_sub(rhs as i64).1)
+        }
+        op::div => (lhs / rhs, false, false),
+        op::mul => {
+            let (result, carry) = lhs.overflowing_mul(rhs);
+            (result, carry, (lhs as i64).overflowing_mul(rhs as i64).1)
+        }
+        op::and => (lhs & rhs, false, false),
+        op::or => (lhs | rhs, false, false),
+        op::xor => (lhs ^ rhs, false, false),
+        op::shr => (lhs.wrapping_shr(rhs as u32), false, false),
+        op::shl => (lhs.wrapping_shl(rhs as u32), false, false),
+        op::divs => {
+            let (result, overflow) = (lhs as i64).overflowing_div(rhs as i64);
+            (result as u64, false, overflow)
+        }
+        op::mods => {
+            let (result, overflow) = (lhs as i64).overflowing_rem(rhs as i64);
+            (result as u64, false, overflow)
+        }
+        op::sar => ((lhs as i64).wrapping_shr(rhs as u32) as u64, false, false),
+        op::r#mod => (lhs % rhs, false, false),
+        op::not => (!lhs, false, false),
+        op::neg => {
+            let (result, overflow) = (lhs as i64).overflowing_neg();
+            (result as u64, lhs != 0, overflow)
+        }
+        op::inc => alu(op::add, lhs, 1),
+        op::dec => alu(op::sub, lhs, 1),
+        op::rol => (lhs.rotate_left((rhs % 64) as u32), false, false),
+        op::ror => (lhs.rotate_right((rhs % 64) as u32), false, false),
+        op::min => (lhs.min(rhs), false, false),
+        op::max => (lhs.max(rhs), false, false),
+        _ => unreachable!("{} is not an ALU instruction", instruction),
+    }
+}
+
+/// The stack at the start of memory: `size` words, addressed in units of
+/// `wordSize` (1 for word addressing, 8 for byte addressing).
+#[derive(Clone, Copy)]
+struct StackRegion {
+    size: usize,
+    wordSize: u64,
+}
+
+impl StackRegion {
+    /// Address of the last stack slot, where `sp` points when the stack is full.
+    fn end(&self) -> u64 {
+        self.size as u64 * self.wordSize
+    }
+}
+
+/// Pushes `value` onto the `sp` stack: `sp` moves up one word and the value goes there.
+/// With byte addressing `sp` may have been set to an unaligned address, so
+/// the check is on where `sp` ends up rather than on `sp` reaching the end.
+fn push(mem: &mut [u64], registers: &mut [u64; registerCount], stack: StackRegion, ip: usize, value: u64) -> Result<(), VmFault> {
+    let Some(top) = registers[spRegister].checked_add(stack.wordSize).filter(|&top| top <= stack.end()) else {
+        return Err(VmFault::StackOverflow { ip, stackSize: stack.size });
+    };
+    registers[spRegister] = top;
+    mem[(registers[spRegister] / stack.wordSize) as usize] = value;
+    Ok(())
+}
+
+/// Pops the word at `sp` and moves `sp` down one word.
+fn pop(mem: &[u64], registers: &mut [u64; registerCount], stack: StackRegion, ip: usize) -> Result<u64, VmFault> {
+    if registers[spRegister] == 0 {
+        return Err(VmFault::StackUnderflow { ip });
+    }
+    if registers[spRegister] > stack.end() {
+        return Err(VmFault::StackOverflow { ip, stackSize: stack.size });
+    }
+    let value = mem[(registers[spRegister] / stack.wordSize) as usize];
+    registers[spRegister] = registers[spRegister].saturating_sub(stack.wordSize);
+    Ok(value)
+}
+
+/// Bytes moved by a load or store, or `None` for a word at a word address.
+fn accessWidth(instruction: u8, byteAddressing: bool) -> Option<u64> {
+    match instruction {
+        op::load8 | op::store8 => Some(1),
+        op::load16 | op::store16 => Some(2),
+        op::load32 | op::store32 => Some(4),
+        op::load64 | op::store64 => Some(8),
+        _ if byteAddressing => Some(8),
+        _ => None,
+    }
+}
+
+/// Reads `width` bytes from byte `address` as a little-endian number. Byte `a`
+/// is bits `(a % 8) * 8` upwards of word `a / 8`, so byte 0 of a word is its
+/// least significant. `None` when any of the bytes is outside `mem`.
+pub fn loadBytes(mem: &[u64], address: u64, width: u64) -> Option<u64> {
+    let last = address.checked_add(width - 1)?;
+    if last / 8 >= mem.len() as u64 {
+        return None;
+    }
+    Some((0..width).fold(0, |value, offset| {
+        let byte = address + offset;
+        let word = mem[(byte / 8) as usize];
+        value | ((word >> ((byte % 8) * 8)) & 0xff) << (offset * 8)
+    }))
+}
+
+/// Writes the low `width` bytes of `value` to byte `address`, little-endian.
+/// Nothing is written when any of the bytes is outside `mem`.
+pub fn storeBytes(mem: &mut [u64], address: u64, width: u64, value: u64) -> Option<()> {
+    let last = address.checked_add(width - 1)?;
+    if last / 8 >= mem.len() as u64 {
+        return None;
+    }
+    for offset in 0..width {
+        let byte = address + offset;
+        let shift = (byte % 8) * 8;
+        let word = &mut mem[(byte / 8) as usize];
+        *word = (*word & !(0xff << shift)) | ((value >> (offset * 8)) & 0xff) << shift;
+    }
+    Some(())
+}
+
+/// Runs interrupt `intCode` with its operand `value`. Results go to `r7`.
+fn interrupt(intCode: u64, value: u64, machine: &mut Machine) -> Result<(), VmFault> {
+    let Machine { registers, mem, heap, console, ip, exitCode, .. } = machine;
+    let ip = *ip;
+    match intCode {
+        0 => *exitCode = Some(value as i32),
+        1 => registers[resultRegister] = heap.allocate(mem, value, ip)?,
+        2 => match u32::try_from(value).ok().and_then(char::from_u32) {
+            Some(character) => console.print(character),
+            None => return Err(VmFault::InvalidCharacter { ip, value }),
+        },
+        3 => console.print(value),
+        6 => console.print(value as i64),
+        10 => console.print(f64::from_bits(value)),
+        4 => registers[resultRegister] = mem.len() as u64 * heap.wordSize(),
+        7 => heap.free(mem, value, ip)?,
+        8 => registers[resultRegister] = heap.reallocate(mem, registers[resultRegister], value, ip)?,
+        9 => {
+            let stats = heap.stats();
+            console.print(format!("{}\n", stats));
+            registers[resultRegister] = stats.usedWords as u64;
         }
-        _ => panic!("invalid interrupt code {}", intCode),
+        11 => registers[resultRegister] = console.readByte().map_or(endOfInput, u64::from),
+        _ => return Err(VmFault::InvalidInterrupt { ip, code: intCode }),
     }
+    Ok(())
 }
 
 
+#[allow(dead_code)]
 struct x86_64Compiler{
 
 }
+#[allow(dead_code)]
 impl x86_64Compiler {
-    pub fn compileToX86_64FromIntermediate(intermediateLanguage: IntermediateLanguage){
+    pub fn compileToX86_64FromIntermediate(_intermediateLanguage: IntermediateLanguage){
+
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parcher;
+
+    fn compile(source: &str) -> Result<ByteCodeCompiler, String> {
+        let intermediate = Parcher::new().parchSource(source, "test.asm")?;
+        let mut program = ByteCodeCompiler::new();
+        program.compileByteCodeFromIntermediate(intermediate)?;
+        Ok(program)
+    }
+
+    /// Runs `source` to its end, returning the final machine.
+    fn run(source: &str) -> Result<Machine, VmFault> {
+        runWith(source, |_| {})
+    }
 
+    /// Runs `source` to its end after `configure` set up the program, as the command line would.
+    fn runWith(source: &str, configure: impl FnOnce(&mut ByteCodeCompiler)) -> Result<Machine, VmFault> {
+        let mut program = compile(source).unwrap();
+        configure(&mut program);
+        let mut machine = program.start()?;
+        program.resume(&mut machine)?;
+        Ok(machine)
+    }
+
+    #[test]
+    fn layoutGivesLabelsTheAddressOfTheNextInstruction() {
+        let program = compile("main:\nset r1 1\nloop:\ndec r1\nend:\nhlt").unwrap();
+        assert_eq!(program.labels["main"], 0);
+        assert_eq!(program.labels["loop"], 1);
+        assert_eq!(program.labels["end"], 2);
+        assert_eq!(program.lines.len(), 3);
+    }
+
+    #[test]
+    fn forwardReferencesAreRelocated() {
+        let program = compile("main:\njmp end\nset r1 1\nend:\nhlt").unwrap();
+        assert_eq!(program.lines[0].arg1, 2);
+        assert!(!program.lines[0].arg1IsReg);
+        assert_eq!(program.relocations.len(), 1);
+        assert_eq!(program.relocations[0].symbol, "end");
+    }
+
+    #[test]
+    fn backwardReferencesAreRelocated() {
+        let program = compile("main:\nset r1 3\nloop:\ndec r1\neq r1 0\njnz loop\nhlt").unwrap();
+        assert_eq!(program.lines[3].arg1, 1);
+    }
+
+    #[test]
+    fn labelsCanBeUsedAsValues() {
+        let program = compile("main:\nset r1 data\nhlt\ndata:\nhlt").unwrap();
+        assert_eq!(program.lines[0].arg2, 2);
+        assert_eq!(program.relocations[0].operand, Operand::Arg2);
+    }
+
+    #[test]
+    fn duplicateLabelsAreRejected() {
+        let error = compile("main:\nhlt\nmain:\nhlt").err().unwrap();
+        assert_eq!(error, "test.asm:3: duplicate label `main`, first defined at test.asm:1");
+    }
+
+    #[test]
+    fn undefinedLabelsAreRejected() {
+        let error = compile("main:\njmp nowhere").err().unwrap();
+        assert_eq!(error, "test.asm:2: undefined label `nowhere`");
+    }
+
+    #[test]
+    fn unknownDirectivesAndInstructionsAreRejected() {
+        let error = compile("#bogus\nmain:\nhlt").err().unwrap();
+        assert_eq!(error, "test.asm:1: unknown directive `#bogus`");
+        let error = compile("main:\nfrobnicate r1\nhlt").err().unwrap();
+        assert_eq!(error, "test.asm:2: unknown instruction `frobnicate`");
+    }
+
+    #[test]
+    fn entryPointsMustBeDefined() {
+        let error = compile("start:\nhlt").err().unwrap();
+        assert_eq!(error, "entry label `main` (the default entry point) is not defined");
+        let error = compile("#entry start\nmain:\nhlt").err().unwrap();
+        assert_eq!(error, "entry label `start` (selected by #entry at test.asm:1) is not defined");
+        let error = compile("#entry main\n#entry start\nmain:\nhlt").err().unwrap();
+        assert_eq!(error, "test.asm:2: duplicate #entry, first given at test.asm:1");
+        let error = compile("#entry\nmain:\nhlt").err().unwrap();
+        assert_eq!(error, "test.asm:1: #entry needs a label");
+    }
+
+    #[test]
+    fn entryLabelOverridesTheEntryDirective() {
+        let intermediate = Parcher::new().parchSource("#entry main\nmain:\nhlt\nstart:\nhlt", "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.entryLabel = Some("start".to_string());
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        assert_eq!(program.entry, Some(1));
+
+        let intermediate = Parcher::new().parchSource("main:\nhlt", "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.entryLabel = Some("x".to_string());
+        let error = program.compileByteCodeFromIntermediate(intermediate).err().unwrap();
+        assert_eq!(error, "entry label `x` (selected with --entry) is not defined");
+    }
+
+    #[test]
+    fn labelDisplacementsBecomeAddends() {
+        let program = compile("main:\nload r1 [table+8]\nstore [table-1] r1\nhlt\ntable:\nhlt").unwrap();
+        assert_eq!(program.lines[0].arg2, 3 + 8);
+        assert!(program.lines[0].addressing.arg2IsMem);
+        assert_eq!(program.relocations[0].addend, 8);
+        assert_eq!(program.lines[1].arg1, 2);
+        assert_eq!(program.relocations[1].addend, 1u64.wrapping_neg());
+    }
+
+    #[test]
+    fn objectsImportUndefinedLabels() {
+        let intermediate = Parcher::new().parchSource("main:\ncall helper\nhlt", "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.object = true;
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        assert_eq!(program.imports, vec!["helper".to_string()]);
+        assert_eq!(program.lines[0].arg1, 0);
+    }
+
+    #[test]
+    fn dividingByZeroIsAFault() {
+        for instruction in ["div", "divs", "mod", "mods"] {
+            let fault = run(&format!("main:\nset r1 5\nset r2 0\n{} r1 r2\nhlt", instruction)).err().unwrap();
+            assert_eq!(fault, VmFault::DivideByZero { ip: 2 }, "{}", instruction);
+            let fault = run(&format!("main:\nset r1 5\n{} r1 0\nhlt", instruction)).err().unwrap();
+            assert_eq!(fault, VmFault::DivideByZero { ip: 1 }, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn negativeLiteralsMayBeHexadecimal() {
+        assert_eq!(ByteCodeCompiler::getLineArgCode("-0x10"), (16u64.wrapping_neg(), false));
+        assert_eq!(ByteCodeCompiler::getLineArgCode("-16"), (16u64.wrapping_neg(), false));
+        let machine = run("main:\nmov r1 -0x10\nhlt").unwrap();
+        assert_eq!(machine.registers[1], 16u64.wrapping_neg());
+    }
+
+    /// Runs one instruction on `r1`, with `operand` as its second operand if
+    /// it takes one, returning `r1` and the flags as (zero, carry, overflow, negative).
+    fn execute(instruction: &str, value: u64, operand: Option<u64>) -> (u64, (bool, bool, bool, bool)) {
+        let line = match operand {
+            Some(operand) => format!("set r2 {:#x}\n{} r1 r2", operand, instruction),
+            None => format!("{} r1", instruction),
+        };
+        let machine = run(&format!("main:\nset r1 {:#x}\n{}\nhlt", value, line)).unwrap();
+        let flags = machine.flags;
+        (machine.registers[1], (flags.zero, flags.carry, flags.overflow, flags.negative))
+    }
+
+    /// Whether `instruction` sets the carrierbit for these operands.
+    fn compare(instruction: &str, lhs: u64, rhs: u64) -> bool {
+        let machine = run(&format!("main:\nset r1 {:#x}\n{} r1 {:#x}\nhlt", lhs, instruction, rhs)).unwrap();
+        machine.carrierbit
+    }
+
+    /// Shared with the console so a test can read what the program printed.
+    #[derive(Clone, Default)]
+    struct Captured(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
-}
\ No newline at end of file
+
+    /// Runs `source` reading `input`, returning the final machine and what it printed.
+    fn runWithConsole(source: &str, input: &str) -> (Machine, String) {
+        let mut program = compile(source).unwrap();
+        let mut machine = program.start().unwrap();
+        let output = Captured::default();
+        machine.console.input = Some(Box::new(io::Cursor::new(input.as_bytes().to_vec())));
+        machine.console.output = Some(Box::new(output.clone()));
+        program.resume(&mut machine).unwrap();
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (machine, printed)
+    }
+
+    const none: (bool, bool, bool, bool) = (false, false, false, false);
+    const zero: (bool, bool, bool, bool) = (true, false, false, false);
+    const negative: (bool, bool, bool, bool) = (false, false, false, true);
+    const minimum: u64 = 1 << 63;
+
+    #[test]
+    fn modTakesTheUnsignedRemainder() {
+        assert_eq!(execute("mod", 17, Some(5)), (2, none));
+        assert_eq!(execute("mod", 15, Some(5)), (0, zero));
+        assert_eq!(execute("mod", u64::MAX, Some(10)), (5, none));
+    }
+
+    #[test]
+    fn notInvertsEveryBit() {
+        assert_eq!(execute("not", 0, None), (u64::MAX, negative));
+        assert_eq!(execute("not", u64::MAX, None), (0, zero));
+        assert_eq!(execute("not", 0xf0, None), (!0xf0, negative));
+    }
+
+    #[test]
+    fn negNegatesWithBorrowAndOverflow() {
+        assert_eq!(execute("neg", 5, None), (5u64.wrapping_neg(), (false, true, false, true)));
+        assert_eq!(execute("neg", 0, None), (0, zero));
+        assert_eq!(execute("neg", 5u64.wrapping_neg(), None), (5, (false, true, false, false)));
+        assert_eq!(execute("neg", minimum, None), (minimum, (false, true, true, true)));
+    }
+
+    #[test]
+    fn incAndDecWrap() {
+        assert_eq!(execute("inc", 41, None), (42, none));
+        assert_eq!(execute("inc", u64::MAX, None), (0, (true, true, false, false)));
+        assert_eq!(execute("inc", i64::MAX as u64, None), (minimum, (false, false, true, true)));
+        assert_eq!(execute("dec", 43, None), (42, none));
+        assert_eq!(execute("dec", 1, None), (0, zero));
+        assert_eq!(execute("dec", 0, None), (u64::MAX, (false, true, false, true)));
+        assert_eq!(execute("dec", minimum, None), (i64::MAX as u64, (false, false, true, false)));
+    }
+
+    #[test]
+    fn rotatesTakeTheAmountModulo64() {
+        assert_eq!(execute("rol", 0x8000_0000_0000_0001, Some(1)), (3, none));
+        assert_eq!(execute("ror", 3, Some(1)), (0x8000_0000_0000_0001, negative));
+        assert_eq!(execute("rol", 0x1234, Some(0)), (0x1234, none));
+        assert_eq!(execute("rol", 0x1234, Some(64)), (0x1234, none));
+        assert_eq!(execute("ror", 0x1234, Some(64)), (0x1234, none));
+        assert_eq!(execute("rol", 1, Some(65)), (2, none));
+        assert_eq!(execute("ror", 1, Some(65)), (minimum, negative));
+        assert_eq!(execute("rol", 1, Some(u64::MAX)), (minimum, negative));
+        assert_eq!(execute("ror", 0, Some(7)), (0, zero));
+    }
+
+    #[test]
+    fn minAndMaxCompareUnsigned() {
+        assert_eq!(execute("min", 3, Some(7)), (3, none));
+        assert_eq!(execute("min", 7, Some(3)), (3, none));
+        assert_eq!(execute("min", u64::MAX, Some(1)), (1, none));
+        assert_eq!(execute("min", 0, Some(1)), (0, zero));
+        assert_eq!(execute("max", 3, Some(7)), (7, none));
+        assert_eq!(execute("max", u64::MAX, Some(1)), (u64::MAX, negative));
+        assert_eq!(execute("max", 0, Some(0)), (0, zero));
+    }
+
+    #[test]
+    fn labelsInMemoryOperandsAreUsed() {
+        let program = compile("main:\nset r2 1\nload r1 [table+r2]\nhlt\ntable:\nhlt\nspare:\nhlt").unwrap();
+        assert_eq!(program.warnings, vec!["test.asm:7: label `spare` is never used".to_string()]);
+    }
+
+    /// One operand of each form: a register, an immediate, a label, a memory
+    /// operand and no operand at all.
+    const operandSamples: [&str; 5] = ["r1", "0", "main", "[r1+2]", ""];
+
+    fn acceptsOperand(kind: OperandKind, sample: &str) -> bool {
+        match kind {
+            OperandKind::None => sample.is_empty(),
+            OperandKind::Register => sample == "r1",
+            OperandKind::Value | OperandKind::Target => matches!(sample, "r1" | "0" | "main"),
+            OperandKind::Address => !sample.is_empty(),
+        }
+    }
+
+    /// An operand `kind` always accepts, for filling the position not under test.
+    fn validOperand(kind: OperandKind) -> &'static str {
+        match kind {
+            OperandKind::None => "",
+            _ => "r2",
+        }
+    }
+
+    #[test]
+    fn emitChecksEveryOperandKindOfEveryInstruction() {
+        for spec in op::instructionTable {
+            let kinds = [spec.operands.0, spec.operands.1];
+            for position in 0..2 {
+                for sample in operandSamples {
+                    let mut operands = [validOperand(kinds[0]), validOperand(kinds[1])];
+                    operands[position] = sample;
+                    let line = format!("{} {} {}", spec.mnemonic, operands[0], operands[1]);
+                    let result = compile(&format!("main:\n{}\nhlt", line.trim_end()));
+                    assert_eq!(
+                        result.is_ok(),
+                        acceptsOperand(kinds[position], sample),
+                        "`{}`: {:?}",
+                        line.trim_end(),
+                        result.err()
+                    );
+                    if let Ok(program) = result {
+                        assert_eq!(program.lines[0].instruction, spec.opcode);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn immediatesAreNotRegisters() {
+        for instruction in ["mov", "set"] {
+            let program = compile(&format!("main:\n{} r1 5\nhlt", instruction)).unwrap();
+            assert!(!program.lines[0].arg2IsReg);
+            let machine = run(&format!("main:\nset r5 99\n{} r1 5\n{} r2 r5\nhlt", instruction, instruction)).unwrap();
+            assert_eq!(machine.registers[1], 5, "{}", instruction);
+            assert_eq!(machine.registers[2], 99, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn invalidInterruptsAreFaults() {
+        let fault = run("main:\nset r8 42\nint 0\nhlt").err().unwrap();
+        assert_eq!(fault, VmFault::InvalidInterrupt { ip: 1, code: 42 });
+        let fault = run("main:\nset r8 0x100\nint 0\nhlt").err().unwrap();
+        assert_eq!(fault, VmFault::InvalidInterrupt { ip: 1, code: 0x100 });
+        let fault = run("main:\nset r8 2\nint 0xd800\nhlt").err().unwrap();
+        assert_eq!(fault, VmFault::InvalidCharacter { ip: 1, value: 0xd800 });
+        let fault = run("main:\nset r8 2\nint 0x100000041\nhlt").err().unwrap();
+        assert_eq!(fault, VmFault::InvalidCharacter { ip: 1, value: 0x100000041 });
+    }
+
+    #[test]
+    fn signedComparisonsTreatTheTopBitAsTheSign() {
+        assert!(compare("bigs", 1, 1u64.wrapping_neg()));
+        assert!(!compare("big", 1, 1u64.wrapping_neg()));
+        assert!(compare("sms", 1u64.wrapping_neg(), 1));
+        assert!(!compare("sm", 1u64.wrapping_neg(), 1));
+        assert!(compare("bigs", i64::MAX as u64, minimum));
+        assert!(!compare("bigs", 5, 5));
+        assert!(!compare("sms", 5, 5));
+    }
+
+    #[test]
+    fn sarKeepsTheSign() {
+        assert_eq!(execute("sar", 16u64.wrapping_neg(), Some(2)), (4u64.wrapping_neg(), negative));
+        assert_eq!(execute("shr", 16u64.wrapping_neg(), Some(2)), ((16u64.wrapping_neg()) >> 2, none));
+        assert_eq!(execute("sar", 16, Some(2)), (4, none));
+        assert_eq!(execute("sar", 1u64.wrapping_neg(), Some(63)), (u64::MAX, negative));
+    }
+
+    #[test]
+    fn signedDivisionRoundsTowardZeroAndWraps() {
+        assert_eq!(execute("divs", 7u64.wrapping_neg(), Some(2)), (3u64.wrapping_neg(), negative));
+        assert_eq!(execute("mods", 7u64.wrapping_neg(), Some(2)), (1u64.wrapping_neg(), negative));
+        assert_eq!(execute("divs", 7, Some(2u64.wrapping_neg())), (3u64.wrapping_neg(), negative));
+        assert_eq!(execute("mods", 7, Some(2u64.wrapping_neg())), (1, none));
+        assert_eq!(execute("divs", minimum, Some(u64::MAX)), (minimum, (false, false, true, true)));
+        assert_eq!(execute("mods", minimum, Some(u64::MAX)), (0, (true, false, true, false)));
+    }
+
+    #[test]
+    fn printSignedShowsNegativeNumbers() {
+        let (_, printed) = runWithConsole("main:\nset r8 6\nint -42\nint 7\nset r8 3\nint 18446744073709551615\nhlt", "");
+        assert_eq!(printed, "-42718446744073709551615");
+    }
+
+    /// The conditional jumps after `cmp`, in the order the table below lists them.
+    const conditionalJumps: [&str; 14] =
+        ["je", "jne", "jl", "jg", "jle", "jge", "jb", "ja", "jc", "jnc", "jo", "jno", "js", "jns"];
+
+    /// Whether `jump` is taken after `comparison`, run as `main`'s first instructions.
+    fn taken(comparison: &str, jump: &str) -> bool {
+        let machine = run(&format!("main:\n{}\n{} yes\nhlt\nyes:\nset r3 1\nhlt", comparison, jump)).unwrap();
+        machine.registers[3] == 1
+    }
+
+    #[test]
+    fn conditionalJumpsReadTheFlagsOfCmp() {
+        let cases: [(&str, [bool; 14]); 5] = [
+            // -1 is less than 1 signed, but above it unsigned.
+            ("cmp -1 1", [false, true, true, false, true, false, false, true, false, true, false, true, true, false]),
+            ("cmp 1 -1", [false, true, false, true, false, true, true, false, true, false, false, true, false, true]),
+            ("cmp 5 5", [true, false, false, false, true, true, false, false, false, true, false, true, false, true]),
+            ("cmp 1 2", [false, true, true, false, true, false, true, false, true, false, false, true, true, false]),
+            // The subtraction overflows, so the negative flag alone would get jl wrong.
+            ("set r1 0x8000000000000000\ncmp r1 1", [false, true, true, false, true, false, false, true, false, true, true, false, false, true]),
+        ];
+        for (comparison, expected) in cases {
+            for (jump, expected) in conditionalJumps.iter().zip(expected) {
+                assert_eq!(taken(comparison, jump), expected, "{} then {}", comparison, jump);
+            }
+        }
+    }
+
+    #[test]
+    fn jzAndJnzFollowTheCarrierbitOnly() {
+        // `cmp` sets the zero flag but leaves the carrierbit alone.
+        assert!(!taken("cmp 5 5", "jz"));
+        assert!(taken("cmp 5 5", "jnz"));
+        assert!(taken("cmp 1 2\neq 3 3", "jz"));
+        assert!(!taken("cmp 1 2\neq 3 3", "jnz"));
+        assert!(taken("big 1 2\ncmp 5 5", "jnz"));
+    }
+
+    /// Everything an instruction can change, for comparing two runs.
+    fn observable(source: &str) -> String {
+        let (machine, printed) = runWithConsole(source, "");
+        format!(
+            "{:?} {:?} {:?} {} {:?} {:?} {:?} {}",
+            machine.registers, machine.mem, machine.flags, machine.carrierbit, machine.callStack, machine.exitCode, machine.ip, printed
+        )
+    }
+
+    #[test]
+    fn registerAndImmediateValuesBehaveAlike() {
+        // r8 selects print as the interrupt, r6 is the store address.
+        let prelude = "main:\nset r1 0x40\nset r5 3\nset r6 100\nset r8 3";
+        for spec in op::instructionTable {
+            let kinds = [spec.operands.0, spec.operands.1];
+            for position in 0..2 {
+                if kinds[position] != OperandKind::Value {
+                    continue;
+                }
+                let operands = |value: &str| {
+                    let mut operands = kinds.map(|kind| match kind {
+                        OperandKind::None => "",
+                        OperandKind::Address => "r6",
+                        _ => "r1",
+                    });
+                    operands[position] = value;
+                    format!("{}\n{} {} {}\nhlt", prelude, spec.mnemonic, operands[0], operands[1])
+                };
+                assert_eq!(observable(&operands("r5")), observable(&operands("3")), "{} operand {}", spec.mnemonic, position + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn registerAndLabelTargetsBehaveAlike() {
+        for spec in op::instructionTable.iter().filter(|spec| spec.operands.0 == OperandKind::Target) {
+            for condition in ["cmp 1 1\neq 1 1", "cmp 1 2\neq 1 2"] {
+                let source = |target: &str| {
+                    format!("main:\nset r5 there\n{}\n{} {}\nset r3 1\nthere:\nset r4 1\nhlt", condition, spec.mnemonic, target)
+                };
+                assert_eq!(observable(&source("r5")), observable(&source("there")), "{} after {}", spec.mnemonic, condition);
+            }
+        }
+    }
+
+    #[test]
+    fn theStackIsBounded() {
+        let fault = runWith("main:\nloop:\npush 1\njmp loop", |program| program.stackSize = 4).err().unwrap();
+        assert_eq!(fault, VmFault::StackOverflow { ip: 0, stackSize: 4 });
+        let fault = runWith("main:\nenter 4\nhlt", |program| program.stackSize = 4).err().unwrap();
+        assert_eq!(fault, VmFault::StackOverflow { ip: 0, stackSize: 4 });
+        let machine = runWith("main:\npush 1\npush 2\npush 3\npush 4\npop r1\nhlt", |program| program.stackSize = 4).unwrap();
+        assert_eq!(machine.registers[1], 4);
+        assert_eq!(machine.registers[spRegister], 3);
+        let fault = run("main:\npush 1\npop r1\npop r1\nhlt").err().unwrap();
+        assert_eq!(fault, VmFault::StackUnderflow { ip: 2 });
+    }
+
+    #[test]
+    fn returningWithoutACallIsAFault() {
+        for callMode in [CallMode::Hidden, CallMode::Stack] {
+            let fault = runWith("main:\nret", |program| program.callMode = callMode).err().unwrap();
+            assert_eq!(fault, VmFault::ReturnWithoutCall { ip: 0 }, "{:?}", callMode);
+        }
+        let fault = runWith("main:\ncall main", |program| {
+            program.callMode = CallMode::Stack;
+            program.stackSize = 8;
+        });
+        assert_eq!(fault.err().unwrap(), VmFault::StackOverflow { ip: 0, stackSize: 8 });
+    }
+
+    /// Calls `f` with a pushed seventh argument; `f` reads it and the slot above it.
+    const frame: &str = "main:\npush 7\ncall f\nhlt\nf:\nenter 2\nstore [fp+1] 11\nstore [fp+2] 12\n\
+                         load r1 [fp-1]\nload r2 [fp-2]\nmov r3 sp\nmov r4 fp\nleave\nret";
+
+    #[test]
+    fn enterBuildsAFrameAboveTheArguments() {
+        let machine = run(frame).unwrap();
+        assert_eq!((machine.registers[1], machine.registers[3], machine.registers[4]), (7, 4, 2));
+        assert_eq!(machine.mem[1..5], [7, 0, 11, 12]);
+        assert_eq!((machine.registers[spRegister], machine.registers[fpRegister]), (1, 0));
+    }
+
+    #[test]
+    fn stackCallsPutTheReturnAddressBelowTheFrame() {
+        let machine = runWith(frame, |program| program.callMode = CallMode::Stack).unwrap();
+        // The seventh argument is at [fp-2], under the return address.
+        assert_eq!((machine.registers[1], machine.registers[2]), (2, 7));
+        assert_eq!((machine.registers[3], machine.registers[4]), (5, 3));
+        assert_eq!(machine.mem[1..6], [7, 2, 0, 11, 12]);
+        assert_eq!((machine.registers[spRegister], machine.registers[fpRegister]), (1, 0));
+        assert!(machine.callStack.is_empty());
+    }
+
+    #[test]
+    fn linkCallsOnlyUseTheLinkRegister() {
+        let source = "main:\ncall f\nset r2 1\nhlt\nf:\nmov r1 lr\nmov r3 sp\nret";
+        let machine = runWith(source, |program| program.callMode = CallMode::Link).unwrap();
+        assert_eq!((machine.registers[1], machine.registers[2], machine.registers[3]), (1, 1, 0));
+        assert!(machine.callStack.is_empty());
+        // A callee that calls on saves `lr` itself.
+        let source = "main:\ncall f\nset r2 1\nhlt\nf:\npush lr\ncall g\npop lr\nret\ng:\ninc r1\nret";
+        let machine = runWith(source, |program| program.callMode = CallMode::Link).unwrap();
+        assert_eq!((machine.registers[1], machine.registers[2]), (1, 1));
+    }
+
+    #[test]
+    fn anUnalignedStackPointerCannotPassTheEnd() {
+        let configure = |program: &mut ByteCodeCompiler| program.byteAddressing = true;
+        let fault = runWith("main:\nmov sp 8191\nenter 0\nhlt", configure).err().unwrap();
+        assert_eq!(fault, VmFault::StackOverflow { ip: 1, stackSize: defaultStackSize });
+        let fault = runWith("main:\nmov sp 8185\npush 1\nhlt", configure).err().unwrap();
+        assert_eq!(fault, VmFault::StackOverflow { ip: 1, stackSize: defaultStackSize });
+        let machine = runWith("main:\nmov sp 8184\nenter 0\nhlt", configure).unwrap();
+        assert_eq!(machine.registers[spRegister], 8192);
+        let fault = run("main:\nmov sp -1\npush 1\nhlt").err().unwrap();
+        assert_eq!(fault, VmFault::StackOverflow { ip: 1, stackSize: defaultStackSize });
+    }
+
+    #[test]
+    fn bytesAreLittleEndian() {
+        let mut mem = vec![0x0807060504030201, 0x100f0e0d0c0b0a09];
+        assert_eq!(loadBytes(&mem, 0, 1), Some(0x01));
+        assert_eq!(loadBytes(&mem, 1, 2), Some(0x0302));
+        assert_eq!(loadBytes(&mem, 0, 8), Some(0x0807060504030201));
+        assert_eq!(storeBytes(&mut mem, 2, 2, 0xbbaa), Some(()));
+        assert_eq!(mem[0], 0x08070605bbaa0201);
+    }
+
+    #[test]
+    fn bytesMayCrossAWordBoundary() {
+        let mut mem = vec![0x0807060504030201, 0x100f0e0d0c0b0a09];
+        assert_eq!(loadBytes(&mem, 6, 4), Some(0x0a090807));
+        assert_eq!(storeBytes(&mut mem, 7, 2, 0xbbaa), Some(()));
+        assert_eq!(mem, vec![0xaa07060504030201, 0x100f0e0d0c0b0abb]);
+    }
+
+    #[test]
+    fn bytesOutsideMemoryAreNone() {
+        let mut mem = vec![0; 2];
+        assert_eq!(loadBytes(&mem, 15, 1), Some(0));
+        assert_eq!(loadBytes(&mem, 16, 1), None);
+        assert_eq!(loadBytes(&mem, 12, 8), None);
+        assert_eq!(loadBytes(&mem, u64::MAX, 2), None);
+        assert_eq!(storeBytes(&mut mem, 12, 8, u64::MAX), None);
+        assert_eq!(storeBytes(&mut mem, u64::MAX - 1, 4, u64::MAX), None);
+        assert_eq!(mem, vec![0; 2]);
+    }
+
+    /// Runs float `instruction` on `value` and `operand`, returning the result as a float.
+    fn float(instruction: &str, value: f64, operand: Option<f64>) -> f64 {
+        let (result, flags) = execute(instruction, value.to_bits(), operand.map(f64::to_bits));
+        assert_eq!(flags, none, "{} changed the flags", instruction);
+        f64::from_bits(result)
+    }
+
+    #[test]
+    fn floatArithmetic() {
+        assert_eq!(float("fadd", 1.5, Some(2.25)), 3.75);
+        assert_eq!(float("fsub", 1.5, Some(2.25)), -0.75);
+        assert_eq!(float("fmul", 1.5, Some(-2.0)), -3.0);
+        assert_eq!(float("fdiv", 3.0, Some(2.0)), 1.5);
+        assert_eq!(float("fdiv", 1.0, Some(0.0)), f64::INFINITY);
+        assert!(float("fdiv", 0.0, Some(0.0)).is_nan());
+        assert_eq!(float("fsqrt", 2.25, None), 1.5);
+        assert!(float("fsqrt", -1.0, None).is_nan());
+    }
+
+    #[test]
+    fn floatComparisonsWithNanAreFalseExceptFneq() {
+        let nan = f64::NAN.to_bits();
+        let (one, two) = (1.0f64.to_bits(), 2.0f64.to_bits());
+        assert!(compare("feq", one, one));
+        assert!(compare("fneq", one, two));
+        assert!(compare("fbig", two, one));
+        assert!(compare("fsm", one, two));
+        // -0.0 and 0.0 have different bits but are equal floats.
+        assert!(compare("feq", (-0.0f64).to_bits(), 0));
+        for (lhs, rhs) in [(nan, one), (one, nan), (nan, nan)] {
+            assert!(!compare("feq", lhs, rhs));
+            assert!(compare("fneq", lhs, rhs));
+            assert!(!compare("fbig", lhs, rhs));
+            assert!(!compare("fsm", lhs, rhs));
+        }
+    }
+
+    #[test]
+    fn conversionsRoundTowardZeroAndSaturate() {
+        assert_eq!(f64::from_bits(execute("itof", 3u64.wrapping_neg(), None).0), -3.0);
+        assert_eq!(execute("ftoi", (-2.75f64).to_bits(), None).0, 2u64.wrapping_neg());
+        assert_eq!(execute("ftoi", 2.75f64.to_bits(), None).0, 2);
+        assert_eq!(execute("ftoi", 1e300f64.to_bits(), None).0, i64::MAX as u64);
+        assert_eq!(execute("ftoi", f64::NEG_INFINITY.to_bits(), None).0, minimum);
+        assert_eq!(execute("ftoi", f64::NAN.to_bits(), None).0, 0);
+    }
+
+    #[test]
+    fn floatLiteralsNeedADecimalPoint() {
+        assert_eq!(parseFloat("2"), None);
+        assert_eq!(parseFloat("2.0"), Some(2.0));
+        assert_eq!(parseFloat("-0.5"), Some(-0.5));
+        assert_eq!(parseFloat(".5"), None);
+        assert_eq!(parseFloat("main.loop"), None);
+        assert_eq!(ByteCodeCompiler::getLineArgCode("2"), (2, false));
+        assert_eq!(ByteCodeCompiler::getLineArgCode("2.0"), (2.0f64.to_bits(), false));
+        let machine = run("main:\nset r1 1.5\nfadd r1 2.5\nset r2 4\nitof r2\nfeq r1 r2\nhlt").unwrap();
+        assert_eq!(f64::from_bits(machine.registers[1]), 4.0);
+        assert!(machine.carrierbit);
+    }
+
+    #[test]
+    fn limitsStopARun() {
+        let fault = runWith("main:\nloop:\njmp loop", |program| program.limits.maxInstructions = Some(10));
+        assert_eq!(fault.err().unwrap(), VmFault::InstructionLimit { ip: 0, limit: 10 });
+        let fault = runWith("main:\ncall main", |program| program.limits.maxCallDepth = Some(5));
+        assert_eq!(fault.err().unwrap(), VmFault::CallDepthLimit { ip: 0, limit: 5 });
+        let fault = runWith("main:\nset r8 1\nint 100\nhlt", |program| program.limits.maxHeapWords = Some(64));
+        assert_eq!(fault.err().unwrap(), VmFault::MemoryLimit { ip: 1, requested: 100, limit: 64 });
+        let fault = runWith("main:\nset r8 1\nint 40\nint 40\nhlt", |program| program.limits.maxHeapWords = Some(64));
+        assert_eq!(fault.err().unwrap(), VmFault::MemoryLimit { ip: 2, requested: 80, limit: 64 });
+        let fault = runWith("main:\nloop:\njmp loop", |program| program.limits.timeout = Some(Duration::ZERO));
+        assert_eq!(fault.err().unwrap(), VmFault::Timeout { ip: 0, limit: Duration::ZERO });
+        let limit = Duration::from_millis(10);
+        let fault = runWith("main:\nloop:\ninc r1\njmp loop", |program| program.limits.timeout = Some(limit));
+        assert!(matches!(fault.err().unwrap(), VmFault::Timeout { ip: 0 | 1, limit: reached } if reached == limit));
+    }
+
+    #[test]
+    fn limitsAllowRunsThatStayUnder() {
+        let configure = |program: &mut ByteCodeCompiler| {
+            program.limits = Limits {
+                maxInstructions: Some(7),
+                maxHeapWords: Some(64),
+                maxCallDepth: Some(1),
+                timeout: Some(Duration::from_secs(60)),
+            }
+        };
+        let machine = runWith("main:\nset r8 1\nint 64\ncall f\ncall f\nhlt\nf:\nret", configure).unwrap();
+        assert_eq!(machine.executed, 7);
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use crate::backends::{self, ByteCodeCompiler};
+use crate::disassembler::disassembleLine;
+use crate::history::History;
+use crate::machine::Machine;
+use crate::watch::{Condition, Watches, Watchpoint};
+use crate::{registerCount, stringToReg};
+
+const help: &str = "\
+step [N]            run N instructions, 1 by default
+back [N]            undo the last N instructions
+continue            run until a breakpoint, a fault or the end
+reverse             run backwards until a breakpoint or the start of history
+lastwrite REG|[ADDR]
+                    run backwards to the instruction that last changed a
+                    register or wrote a memory address
+break TARGET [if CONDITION]
+                    stop at an instruction address or label, only when
+                    CONDITION holds if one is given
+delete TARGET       remove a breakpoint
+watch ADDR          stop after an instruction writes memory address ADDR
+rwatch ADDR         stop after an instruction reads ADDR
+awatch ADDR         stop after an instruction reads or writes ADDR
+when CONDITION      stop when CONDITION becomes true, such as `r1 == 42`
+watches             list the watchpoints and conditions
+unwatch N           remove watch number N
+regs                show the registers and flags
+mem ADDR [N]        show N words from address ADDR, 1 by default
+save FILE           write a snapshot of the current state
+quit                stop debugging";
+
+/// Interactive debugger over a recorded run. Commands come from standard input,
+/// which the program shares for its own reads, and everything the debugger
+/// prints goes to standard error so it stays apart from the program's output.
+pub struct Debugger<'a> {
+    program: &'a mut ByteCodeCompiler,
+    machine: Machine,
+    history: History,
+    /// Breakpoints by address, with the condition they stop on if any.
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+    watches: Watches,
+    /// Labels by address, for showing where the machine is.
+    labels: HashMap<usize, String>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a mut ByteCodeCompiler, machine: Machine, historyCapacity: usize) -> Debugger<'a> {
+        let labels = program.labels.iter().map(|(label, &address)| (address, label.clone())).collect();
+        Debugger {
+            program,
+            machine,
+            history: History::new(historyCapacity),
+            breakpoints: BTreeMap::new(),
+            watches: Watches::default(),
+            labels,
+        }
+    }
+
+    /// Reads and runs commands until `quit` or the end of standard input.
+    pub fn run(&mut self) {
+        self.showLocation();
+        loop {
+            io::stdout().flush().ok();
+            eprint!("(nis) ");
+            let mut command = String::new();
+            if io::stdin().lock().read_line(&mut command).unwrap_or(0) == 0 {
+                break;
+            }
+            let words: Vec<&str> = command.split_whitespace().collect();
+            let Some((&name, arguments)) = words.split_first() else {
+                continue;
+            };
+            if matches!(name, "quit" | "q") {
+                break;
+            }
+            if let Err(error) = self.command(name, arguments) {
+                eprintln!("error: {}", error);
+            }
+        }
+    }
+
+    fn command(&mut self, name: &str, arguments: &[&str]) -> Result<(), String> {
+        let count = || arguments.first().map_or(Ok(1), |count| count.parse::<usize>().map_err(|_| format!("invalid count `{}`", count)));
+        match name {
+            "step" | "s" => {
+                for _ in 0..count()? {
+                    if !self.forward() {
+                        break;
+                    }
+                }
+            }
+            "back" | "b" => {
+                for _ in 0..count()? {
+                    if !self.backward() {
+                        break;
+                    }
+                }
+            }
+            "continue" | "c" => while self.forward() && !self.atBreakpoint() {},
+            "reverse" | "rc" => while self.backward() && !self.atBreakpoint() {},
+            "lastwrite" | "lw" => {
+                let target = arguments.first().ok_or("lastwrite needs a register or [address]")?;
+                self.lastWrite(target)?;
+            }
+            "break" | "br" => {
+                let (target, condition) = match arguments.iter().position(|&word| word == "if") {
+                    Some(index) => (&arguments[..index], Some(Condition::parse(&arguments[index + 1..].join(" "))?)),
+                    None => (arguments, None),
+                };
+                let address = self.target(target)?;
+                match &condition {
+                    Some(condition) => eprintln!("breakpoint at {} if {}", address, condition),
+                    None => eprintln!("breakpoint at {}", address),
+                }
+                self.breakpoints.insert(address, condition);
+                return Ok(());
+            }
+            "delete" | "d" => {
+                let address = self.target(arguments)?;
+                if self.breakpoints.remove(&address).is_none() {
+                    return Err(format!("no breakpoint at {}", address));
+                }
+                return Ok(());
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let address = arguments.first().ok_or_else(|| format!("{} needs an address", name))?;
+                let address = address.strip_prefix('[').and_then(|address| address.strip_suffix(']')).unwrap_or(address);
+                self.watches.addWatchpoint(Watchpoint {
+                    address: parseNumber(address)?,
+                    read: name != "watch",
+                    write: name != "rwatch",
+                });
+                return Ok(());
+            }
+            "when" => {
+                let condition = Condition::parse(&arguments.join(" "))?;
+                self.watches.addCondition(condition, &self.machine);
+                return Ok(());
+            }
+            "watches" => {
+                for watch in self.watches.list() {
+                    eprintln!("{}", watch);
+                }
+                return Ok(());
+            }
+            "unwatch" => {
+                let number = arguments.first().ok_or("unwatch needs a watch number")?;
+                return self.watches.remove(parseNumber(number)? as usize);
+            }
+            "regs" | "r" => {
+                self.showRegisters();
+                return Ok(());
+            }
+            "mem" | "m" => {
+                let address = arguments.first().ok_or("mem needs an address")?;
+                let address = parseNumber(address)?;
+                let count = arguments.get(1).map_or(Ok(1), |count| parseNumber(count))?;
+                self.showMemory(address, count);
+                return Ok(());
+            }
+            "save" => {
+                let path = arguments.first().ok_or("save needs a file name")?;
+                self.program
+                    .writeSnapshotToFile(&self.machine, path)
+                    .map_err(|error| format!("could not write {}: {}", path, error))?;
+                return Ok(());
+            }
+            "help" | "h" => {
+                eprintln!("{}", help);
+                return Ok(());
+            }
+            _ => return Err(format!("unknown command `{}`, try `help`", name)),
+        }
+        self.showLocation();
+        Ok(())
+    }
+
+    /// Runs one instruction, reporting why when there is none to run or it
+    /// triggered a watch, and returning whether to go on.
+    fn forward(&mut self) -> bool {
+        if !self.program.isRunning(&self.machine) {
+            match self.machine.exitCode {
+                Some(code) => eprintln!("the program has exited with code {}", code),
+                None => eprintln!("the program has ended"),
+            }
+            return false;
+        }
+        let ip = self.machine.ip;
+        let pending = (!self.watches.isEmpty()).then(|| self.watches.before(self.program, &self.machine));
+        if let Err(fault) = self.program.recordedStep(&mut self.machine, &mut self.history) {
+            eprintln!("fault: {}", fault);
+            return false;
+        }
+        match pending {
+            Some(pending) => {
+                let hits = self.watches.after(&self.machine, pending, ip);
+                report(hits)
+            }
+            None => true,
+        }
+    }
+
+    /// Undoes one instruction, returning whether to go on like `forward`.
+    /// Going backwards, a watchpoint stops before the instruction that
+    /// accessed it and a condition where it becomes true again.
+    fn backward(&mut self) -> bool {
+        let Some(record) = self.history.last() else {
+            eprintln!("at the start of the recorded history");
+            return false;
+        };
+        if self.watches.isEmpty() {
+            return self.history.stepBack(&mut self.machine);
+        }
+        let wordSize = self.machine.heap.wordSize();
+        let ip = record.ip;
+        let mut hits: Vec<String> = self
+            .watches
+            .watchpoints()
+            .filter(|watchpoint| watchpoint.write && record.wroteWord(&self.machine, (watchpoint.address / wordSize) as usize))
+            .map(|watchpoint| format!("write of [{}] at ip {}", watchpoint.address, ip))
+            .collect();
+        self.history.stepBack(&mut self.machine);
+        let reads = self.program.readWords(&self.machine);
+        hits.extend(
+            self.watches
+                .watchpoints()
+                .filter(|watchpoint| watchpoint.read && reads.contains(&((watchpoint.address / wordSize) as usize)))
+                .map(|watchpoint| format!("read of [{}] at ip {}", watchpoint.address, ip)),
+        );
+        hits.extend(self.watches.conditions(&self.machine));
+        report(hits)
+    }
+
+    /// Whether there is a breakpoint at `ip` whose condition, if any, holds.
+    fn atBreakpoint(&self) -> bool {
+        match self.breakpoints.get(&self.machine.ip) {
+            Some(Some(condition)) => condition.holds(&self.machine),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Steps back over the instruction that last changed register `target`,
+    /// or wrote memory address `[target]`, stopping just before it.
+    fn lastWrite(&mut self, target: &str) -> Result<(), String> {
+        let wordSize = self.machine.heap.wordSize();
+        let register = stringToReg.iter().position(|&register| register == target);
+        let word = match target.strip_prefix('[').and_then(|address| address.strip_suffix(']')) {
+            Some(address) => Some((parseNumber(address)? / wordSize) as usize),
+            None if register.is_none() => return Err(format!("`{}` is not a register or [address]", target)),
+            None => None,
+        };
+        loop {
+            let Some(record) = self.history.last() else {
+                eprintln!("no recorded write to {}", target);
+                return Ok(());
+            };
+            let wrote = match (register, word) {
+                (Some(register), _) => record.changedRegister(register),
+                (_, Some(word)) => record.wroteWord(&self.machine, word),
+                _ => unreachable!(),
+            };
+            self.history.stepBack(&mut self.machine);
+            if wrote {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Address of a breakpoint given as a number or a label.
+    fn target(&self, arguments: &[&str]) -> Result<usize, String> {
+        let target = arguments.first().ok_or("needs an address or label")?;
+        match self.program.labels.get(*target) {
+            Some(&address) => Ok(address),
+            None => parseNumber(target).map(|address| address as usize),
+        }
+    }
+
+    fn showLocation(&self) {
+        let ip = self.machine.ip;
+        let label = self.labels.get(&ip).map_or(String::new(), |label| format!(" <{}>", label));
+        match self.program.lines.get(ip) {
+            Some(line) => {
+                let labels = self.labels.iter().map(|(address, label)| (*address, label)).collect();
+                eprintln!("{}{}: {}", ip, label, disassembleLine(line, &labels));
+            }
+            None => eprintln!("{}: end of program", ip),
+        }
+    }
+
+    fn showRegisters(&self) {
+        let registers: Vec<String> = (0..registerCount)
+            .map(|register| format!("{:>4} {:<20}", stringToReg[register], self.machine.register(register)))
+            .collect();
+        for row in registers.chunks(4) {
+            eprintln!("{}", row.concat().trim_end());
+        }
+        let flags = self.machine.flags;
+        eprintln!(
+            "carrierbit {} zero {} carry {} overflow {} negative {}",
+            self.machine.carrierbit as u8, flags.zero as u8, flags.carry as u8, flags.overflow as u8, flags.negative as u8
+        );
+    }
+
+    fn showMemory(&self, address: u64, count: u64) {
+        let wordSize = self.machine.heap.wordSize();
+        for word in (address / wordSize)..(address / wordSize).saturating_add(count) {
+            match self.machine.mem.get(word as usize) {
+                Some(value) => eprintln!("[{}] {}", word * wordSize, value),
+                None => {
+                    eprintln!("[{}] is past the end of memory", word * wordSize);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Prints the watches an instruction triggered, returning whether there were none.
+fn report(hits: Vec<String>) -> bool {
+    for hit in &hits {
+        eprintln!("watch: {}", hit);
+    }
+    hits.is_empty()
+}
+
+fn parseNumber(text: &str) -> Result<u64, String> {
+    backends::parseNumber(text).ok_or_else(|| format!("invalid number `{}`", text))
+}
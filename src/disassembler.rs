@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use crate::backends::ByteCodeCompiler;
+use crate::instructions::{self as op, byOpcode, InstructionSpec, OperandKind};
+use crate::{stringToReg, Addressing, Line};
+
+/// Number of operands written after each mnemonic.
+fn operandCount(spec: &InstructionSpec) -> usize {
+    match spec.operands {
+        (OperandKind::None, _) => 0,
+        (_, OperandKind::None) => 1,
+        _ => 2,
+    }
+}
+
+fn operandText(value: u64, isReg: bool, labels: &HashMap<usize, &String>) -> String {
+    if isReg {
+        return match stringToReg.get(value as usize) {
+            Some(register) => register.to_string(),
+            None => format!("r?{}", value),
+        };
+    }
+    match labels.get(&(value as usize)) {
+        Some(label) => format!("{} ; {}", value, label),
+        None => value.to_string(),
+    }
+}
+
+/// Immediates of float arithmetic and comparisons are f64 bit patterns.
+fn isFloatOperand(instruction: u8, isReg: bool) -> bool {
+    !isReg
+        && matches!(instruction, op::fadd | op::fsub | op::fmul | op::fdiv | op::feq | op::fneq | op::fbig | op::fsm)
+}
+
+fn memoryOperandText(displacement: u64, addressing: &Addressing) -> String {
+    let register = |register: u8| stringToReg.get(register as usize).map_or(format!("r?{}", register), |r| r.to_string());
+    let mut terms = vec![];
+    if let Some(base) = addressing.base {
+        terms.push(register(base));
+    }
+    if let Some(index) = addressing.index {
+        terms.push(format!("{}*{}", register(index), addressing.scale));
+    }
+    if displacement != 0 || terms.is_empty() {
+        terms.push(displacement.to_string());
+    }
+    format!("[{}]", terms.join("+"))
+}
+
+/// Renders one instruction the way it would be written in an ASM file.
+pub fn disassembleLine(line: &Line, labels: &HashMap<usize, &String>) -> String {
+    let Some(spec) = byOpcode(line.instruction) else {
+        return format!("<invalid opcode {}>", line.instruction);
+    };
+    let noLabels = HashMap::new();
+    let mut text = spec.mnemonic.to_string();
+    if operandCount(spec) >= 1 {
+        let labels = if spec.operands.0 == OperandKind::Target { labels } else { &noLabels };
+        text += " ";
+        if line.addressing.arg1IsMem {
+            text += &memoryOperandText(line.arg1, &line.addressing);
+        } else {
+            text += &if isFloatOperand(line.instruction, line.arg1IsReg) {
+                format!("{:?}", f64::from_bits(line.arg1))
+            } else {
+                operandText(line.arg1, line.arg1IsReg, labels)
+            };
+        }
+    }
+    if operandCount(spec) >= 2 {
+        text += " ";
+        if line.addressing.arg2IsMem {
+            text += &memoryOperandText(line.arg2, &line.addressing);
+        } else {
+            text += &if isFloatOperand(line.instruction, line.arg2IsReg) {
+                format!("{:?}", f64::from_bits(line.arg2))
+            } else {
+                operandText(line.arg2, line.arg2IsReg, &noLabels)
+            };
+        }
+    }
+    text
+}
+
+/// Disassembles a whole program, one instruction per line prefixed with its
+/// address. Labels are shown when the program still knows them.
+pub fn disassemble(program: &ByteCodeCompiler) -> String {
+    let labels: HashMap<usize, &String> = program.labels.iter().map(|(name, address)| (*address, name)).collect();
+    let mut output = String::new();
+    match program.entry {
+        Some(entry) => output += &format!("; entry point: {}\n", entry),
+        None => output += "; entry point: none (library)\n",
+    }
+    for (index, line) in program.lines.iter().enumerate() {
+        if let Some(label) = labels.get(&index) {
+            output += &format!("{}:\n", label);
+        }
+        output += &format!("{:>5}    {}\n", index, disassembleLine(line, &labels));
+    }
+    output
+}
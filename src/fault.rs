@@ -0,0 +1,133 @@
+use std::fmt;
+use std::time::Duration;
+use crate::verifier::VerifyError;
+
+/// Why the VM stopped before the program halted. Every fault carries the `ip`
+/// of the instruction that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmFault {
+    StackOverflow { ip: usize, stackSize: usize },
+    StackUnderflow { ip: usize },
+    MemoryOutOfBounds { ip: usize, address: u64, size: usize },
+    ReturnWithoutCall { ip: usize },
+    InvalidFree { ip: usize, address: u64 },
+    DoubleFree { ip: usize, address: u64 },
+    UseAfterFree { ip: usize, address: u64 },
+    /// The verifier rejected the program before it started.
+    InvalidBytecode { errors: Vec<VerifyError> },
+    /// The run reached the instruction limit in `Limits`.
+    InstructionLimit { ip: usize, limit: u64 },
+    /// An allocation would take the live heap past the memory limit, in words.
+    MemoryLimit { ip: usize, requested: usize, limit: usize },
+    CallDepthLimit { ip: usize, limit: usize },
+    Timeout { ip: usize, limit: Duration },
+    /// `div`, `divs`, `mod` or `mods` with a zero divisor.
+    DivideByZero { ip: usize },
+    /// `int` with a code in `r8` that no interrupt has.
+    InvalidInterrupt { ip: usize, code: u64 },
+    /// The print character interrupt with a value that is not a Unicode scalar value.
+    InvalidCharacter { ip: usize, value: u64 },
+}
+
+impl VmFault {
+    /// Process exit code the CLI uses for this fault, distinct per kind. They
+    /// start at 201, clear of 101 for an interpreter panic and 128 + n for a signal.
+    pub fn exitCode(&self) -> i32 {
+        match self {
+            VmFault::StackOverflow { .. } => 201,
+            VmFault::StackUnderflow { .. } => 202,
+            VmFault::MemoryOutOfBounds { .. } => 203,
+            VmFault::ReturnWithoutCall { .. } => 204,
+            VmFault::InvalidFree { .. } => 205,
+            VmFault::DoubleFree { .. } => 206,
+            VmFault::UseAfterFree { .. } => 207,
+            VmFault::InvalidBytecode { .. } => 208,
+            VmFault::InstructionLimit { .. } => 209,
+            VmFault::MemoryLimit { .. } => 210,
+            VmFault::CallDepthLimit { .. } => 211,
+            VmFault::Timeout { .. } => 212,
+            VmFault::DivideByZero { .. } => 213,
+            VmFault::InvalidInterrupt { .. } => 214,
+            VmFault::InvalidCharacter { .. } => 215,
+        }
+    }
+}
+
+impl fmt::Display for VmFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmFault::StackOverflow { ip, stackSize } => {
+                write!(f, "stack overflow at ip {} (stack size {} words)", ip, stackSize)
+            }
+            VmFault::StackUnderflow { ip } => write!(f, "stack underflow at ip {}", ip),
+            VmFault::MemoryOutOfBounds { ip, address, size } => {
+                write!(f, "memory access at {} out of bounds at ip {} (memory size {} words)", address, ip, size)
+            }
+            VmFault::ReturnWithoutCall { ip } => write!(f, "ret with empty call stack at ip {}", ip),
+            VmFault::InvalidFree { ip, address } => {
+                write!(f, "free of {} at ip {}, which is not an allocated block", address, ip)
+            }
+            VmFault::DoubleFree { ip, address } => write!(f, "double free of {} at ip {}", address, ip),
+            VmFault::UseAfterFree { ip, address } => {
+                write!(f, "use after free: access to {} at ip {}", address, ip)
+            }
+            VmFault::InvalidBytecode { errors } => {
+                write!(f, "invalid bytecode")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+            VmFault::InstructionLimit { ip, limit } => {
+                write!(f, "instruction limit of {} reached at ip {}", limit, ip)
+            }
+            VmFault::MemoryLimit { ip, requested, limit } => {
+                write!(f, "allocation at ip {} would use {} heap words, over the limit of {}", ip, requested, limit)
+            }
+            VmFault::CallDepthLimit { ip, limit } => {
+                write!(f, "call depth limit of {} reached at ip {}", limit, ip)
+            }
+            VmFault::Timeout { ip, limit } => {
+                write!(f, "timed out after {:?} at ip {}", limit, ip)
+            }
+            VmFault::DivideByZero { ip } => write!(f, "division by zero at ip {}", ip),
+            VmFault::InvalidInterrupt { ip, code } => write!(f, "invalid interrupt code {} at ip {}", code, ip),
+            VmFault::InvalidCharacter { ip, value } => {
+                write!(f, "{:#x} at ip {} is not a character that can be printed", value, ip)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmFault {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everyFaultHasItsOwnExitCode() {
+        let faults = [
+            VmFault::StackOverflow { ip: 0, stackSize: 0 },
+            VmFault::StackUnderflow { ip: 0 },
+            VmFault::MemoryOutOfBounds { ip: 0, address: 0, size: 0 },
+            VmFault::ReturnWithoutCall { ip: 0 },
+            VmFault::InvalidFree { ip: 0, address: 0 },
+            VmFault::DoubleFree { ip: 0, address: 0 },
+            VmFault::UseAfterFree { ip: 0, address: 0 },
+            VmFault::InvalidBytecode { errors: vec![] },
+            VmFault::InstructionLimit { ip: 0, limit: 0 },
+            VmFault::MemoryLimit { ip: 0, requested: 0, limit: 0 },
+            VmFault::CallDepthLimit { ip: 0, limit: 0 },
+            VmFault::Timeout { ip: 0, limit: Duration::ZERO },
+            VmFault::DivideByZero { ip: 0 },
+            VmFault::InvalidInterrupt { ip: 0, code: 0 },
+            VmFault::InvalidCharacter { ip: 0, value: 0 },
+        ];
+        let mut codes: Vec<i32> = faults.iter().map(VmFault::exitCode).collect();
+        assert!(codes.iter().all(|code| (201..=255).contains(code)), "{:?}", codes);
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), faults.len());
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::backends::{loadBytes, storeBytes, ByteCodeCompiler, Flags};
+use crate::machine::Machine;
+use crate::{ipRegister, registerCount, stringToReg};
+
+/// Register number of the flags after the general registers, as GDB counts them.
+const flagsRegister: usize = registerCount;
+/// Instructions run between looks for an interrupt from GDB while continuing.
+const interruptCheckInterval: u64 = 4096;
+
+/// Signals reported in stop replies.
+const sigint: u8 = 2;
+const sigtrap: u8 = 5;
+const sigsegv: u8 = 11;
+
+/// Target description served to GDB through `qXfer:features:read`, built from
+/// the register table: the registers in encoding order, `ip` as the program
+/// counter, then `carrierbit` and the flags packed into one 32-bit register.
+pub fn targetXml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.nis.core\">\n    <flags id=\"nis_flags\" size=\"4\">\n      <field name=\"carrierbit\" start=\"0\" end=\"0\"/>\n      <field name=\"zero\" start=\"1\" end=\"1\"/>\n      <field name=\"carry\" start=\"2\" end=\"2\"/>\n      <field name=\"overflow\" start=\"3\" end=\"3\"/>\n      <field name=\"negative\" start=\"4\" end=\"4\"/>\n    </flags>\n",
+    );
+    for (number, name) in stringToReg.iter().enumerate() {
+        let (kind, generic) = match *name {
+            "sp" => ("data_ptr", " generic=\"sp\""),
+            "fp" => ("data_ptr", " generic=\"fp\""),
+            "lr" => ("code_ptr", " generic=\"ra\""),
+            "ip" => ("code_ptr", " generic=\"pc\""),
+            _ => ("uint64", ""),
+        };
+        xml += &format!("    <reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"{}/>\n", name, kind, number, generic);
+    }
+    xml += &format!("    <reg name=\"flags\" bitsize=\"32\" type=\"nis_flags\" regnum=\"{}\"/>\n", flagsRegister);
+    xml += "  </feature>\n</target>\n";
+    xml
+}
+
+/// How the stub talks to GDB.
+enum Connection {
+    Tcp(TcpStream),
+    Stdio,
+}
+
+/// A GDB remote serial protocol server for one program. Memory is shown to
+/// GDB byte-addressed as in `--byte-addressing`, so word `w` is at `8w`, and
+/// breakpoint and program counter addresses are instruction indices.
+pub struct GdbStub<'a> {
+    program: &'a mut ByteCodeCompiler,
+    machine: Machine,
+    connection: Connection,
+    reader: BufReader<Box<dyn Read>>,
+    breakpoints: BTreeSet<usize>,
+    /// Set by `QStartNoAckMode`: packets are no longer acknowledged.
+    noAck: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    /// Waits for GDB to connect to `127.0.0.1:port`.
+    pub fn listen(program: &'a mut ByteCodeCompiler, machine: Machine, port: u16) -> io::Result<GdbStub<'a>> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("waiting for gdb on {}", listener.local_addr()?);
+        GdbStub::accept(program, machine, &listener)
+    }
+
+    /// Waits for GDB to connect through `listener`.
+    pub fn accept(program: &'a mut ByteCodeCompiler, machine: Machine, listener: &TcpListener) -> io::Result<GdbStub<'a>> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let reader: Box<dyn Read> = Box::new(stream.try_clone()?);
+        Ok(GdbStub::new(program, machine, Connection::Tcp(stream), reader))
+    }
+
+    /// Talks to GDB over standard input and output, as with
+    /// `target remote | nis ...`. The program's own output goes to standard
+    /// error and it reads nothing unless given an input file.
+    pub fn stdio(program: &'a mut ByteCodeCompiler, mut machine: Machine) -> GdbStub<'a> {
+        machine.console.output = Some(Box::new(io::stderr()));
+        if machine.console.input.is_none() {
+            machine.console.input = Some(Box::new(io::empty()));
+        }
+        GdbStub::new(program, machine, Connection::Stdio, Box::new(io::stdin()))
+    }
+
+    fn new(program: &'a mut ByteCodeCompiler, machine: Machine, connection: Connection, reader: Box<dyn Read>) -> GdbStub<'a> {
+        GdbStub {
+            program,
+            machine,
+            connection,
+            reader: BufReader::new(reader),
+            breakpoints: BTreeSet::new(),
+            noAck: false,
+        }
+    }
+
+    /// Answers packets until GDB detaches, kills the program or hangs up.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(&packet),
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// The reply to one packet. Empty tells GDB the packet isn't supported.
+    fn handle(&mut self, packet: &str) -> String {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => self.stopReply(sigtrap),
+            "g" => (0..=flagsRegister).map(|register| self.readRegister(register)).collect(),
+            "G" => {
+                let mut arguments = arguments;
+                for register in 0..=flagsRegister {
+                    let width = if register == flagsRegister { 8 } else { 16 };
+                    let Some(value) = arguments.get(..width).and_then(decodeLittleEndian) else {
+                        return "E01".to_string();
+                    };
+                    self.writeRegister(register, value);
+                    arguments = &arguments[width..];
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register <= flagsRegister => self.readRegister(register),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = arguments.split_once('=').and_then(|(register, value)| {
+                    Some((usize::from_str_radix(register, 16).ok()?, decodeLittleEndian(value)?))
+                });
+                match parsed {
+                    Some((register, value)) if register <= flagsRegister => {
+                        self.writeRegister(register, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => {
+                let Some((address, length)) = parseRange(arguments) else {
+                    return "E01".to_string();
+                };
+                let bytes: Vec<u8> = (address..address.saturating_add(length))
+                    .map_while(|address| loadBytes(&self.machine.mem, address, 1).map(|byte| byte as u8))
+                    .collect();
+                if bytes.is_empty() && length > 0 {
+                    return "E01".to_string();
+                }
+                encodeHex(&bytes)
+            }
+            "M" => {
+                let parsed = arguments.split_once(':').and_then(|(range, data)| Some((parseRange(range)?, decodeHex(data)?)));
+                let Some(((address, _), bytes)) = parsed.filter(|((_, length), bytes)| *length == bytes.len() as u64) else {
+                    return "E01".to_string();
+                };
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    if storeBytes(&mut self.machine.mem, address + offset as u64, 1, byte as u64).is_none() {
+                        return "E01".to_string();
+                    }
+                }
+                "OK".to_string()
+            }
+            "s" => self.resume(true),
+            "c" => self.resume(false),
+            "Z" | "z" => {
+                let mut fields = arguments.split(',');
+                let (Some("0" | "1"), Some(address)) = (fields.next(), fields.next()) else {
+                    return String::new();
+                };
+                let Ok(address) = usize::from_str_radix(address, 16) else {
+                    return "E01".to_string();
+                };
+                if command == "Z" {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                "OK".to_string()
+            }
+            "H" | "T" => "OK".to_string(),
+            _ => self.query(packet),
+        }
+    }
+
+    /// General queries and settings, `q` and `Q` packets.
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parseRange(range) else {
+                return "E01".to_string();
+            };
+            let xml = targetXml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(length as usize).min(xml.len());
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.noAck = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Runs one instruction, or until a breakpoint, and says why it stopped.
+    fn resume(&mut self, single: bool) -> String {
+        loop {
+            if !self.program.isRunning(&self.machine) {
+                return format!("W{:02x}", self.machine.exitCode.unwrap_or(0) as u8);
+            }
+            if let Err(fault) = self.program.step(&mut self.machine) {
+                eprintln!("fault: {}", fault);
+                return self.stopReply(sigsegv);
+            }
+            if single {
+                return self.stopReply(sigtrap);
+            }
+            if self.breakpoints.contains(&self.machine.ip) && self.machine.exitCode.is_none() {
+                // `qSupported` offers `swbreak`, so say the stop was a software breakpoint.
+                return format!("T{:02x}swbreak:;", sigtrap);
+            }
+            if self.machine.executed.is_multiple_of(interruptCheckInterval) && self.interrupted() {
+                return self.stopReply(sigint);
+            }
+        }
+    }
+
+    fn stopReply(&self, signal: u8) -> String {
+        match self.machine.exitCode {
+            Some(code) => format!("W{:02x}", code as u8),
+            None => format!("S{:02x}", signal),
+        }
+    }
+
+    /// Whether GDB has sent an interrupt, a 0x03 byte outside a packet. Only
+    /// noticed over TCP; standard input can't be polled without blocking.
+    fn interrupted(&mut self) -> bool {
+        let Connection::Tcp(stream) = &self.connection else {
+            return false;
+        };
+        let mut byte = [0u8; 1];
+        stream.set_nonblocking(true).ok();
+        let peeked = stream.peek(&mut byte);
+        stream.set_nonblocking(false).ok();
+        if matches!(peeked, Ok(1)) && byte[0] == 0x03 {
+            self.reader.read_exact(&mut byte).ok();
+            return true;
+        }
+        false
+    }
+
+    fn readRegister(&self, register: usize) -> String {
+        if register == flagsRegister {
+            let flags = self.machine.flags;
+            let bits = [self.machine.carrierbit, flags.zero, flags.carry, flags.overflow, flags.negative]
+                .iter()
+                .enumerate()
+                .fold(0u32, |bits, (bit, &set)| bits | (set as u32) << bit);
+            return encodeHex(&bits.to_le_bytes());
+        }
+        let value = self.machine.register(register);
+        encodeHex(&value.to_le_bytes())
+    }
+
+    /// Writing `ip` moves the program counter.
+    fn writeRegister(&mut self, register: usize, value: u64) {
+        if register == flagsRegister {
+            let bit = |bit: u32| value & (1 << bit) != 0;
+            self.machine.carrierbit = bit(0);
+            self.machine.flags = Flags { zero: bit(1), carry: bit(2), overflow: bit(3), negative: bit(4) };
+        } else if register == ipRegister {
+            self.machine.ip = value as usize;
+        } else {
+            self.machine.registers[register] = value;
+        }
+    }
+
+    /// Reads the next packet, acknowledging it, `None` when GDB hangs up.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+        loop {
+            // Skip acknowledgements and interrupts that arrive while stopped.
+            loop {
+                if self.reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = vec![];
+            loop {
+                self.reader.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            let valid = expected == Some(data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+            if !self.noAck {
+                self.write(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let mut data = vec![];
+        for &byte in reply.as_bytes() {
+            if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+                data.extend([b'}', byte ^ 0x20]);
+            } else {
+                data.push(byte);
+            }
+        }
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let mut packet = vec![b'$'];
+        packet.extend(data);
+        packet.extend(format!("#{:02x}", checksum).bytes());
+        self.write(&packet)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match &mut self.connection {
+            Connection::Tcp(stream) => stream.write_all(bytes),
+            Connection::Stdio => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(bytes)?;
+                stdout.flush()
+            }
+        }
+    }
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = data.iter();
+    let mut unescaped = vec![];
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|&byte| byte ^ 0x20)),
+            _ => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+/// Parses `address,length` in hex.
+fn parseRange(text: &str) -> Option<(u64, u64)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u64::from_str_radix(address, 16).ok()?, u64::from_str_radix(length, 16).ok()?))
+}
+
+fn encodeHex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decodeHex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+/// A register value as GDB sends it: target-endian bytes in hex, up to 8 of them.
+fn decodeLittleEndian(text: &str) -> Option<u64> {
+    let bytes = decodeHex(text).filter(|bytes| bytes.len() <= 8)?;
+    Some(bytes.iter().rev().fold(0u64, |value, &byte| value << 8 | byte as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::Parcher;
+
+    /// A scripted GDB: sends each packet and checks the reply.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, packet: &str) -> String {
+            let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            self.stream.write_all(format!("${}#{:02x}", packet, checksum).as_bytes()).unwrap();
+            assert_eq!(self.byte(), b'+', "{} was not acknowledged", packet);
+            assert_eq!(self.byte(), b'$');
+            let mut reply = vec![];
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b'}' => reply.push(self.byte() ^ 0x20),
+                    byte => reply.push(byte),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let expected = reply.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", expected));
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0u8; 1];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    /// Serves `source` on a free port to `script`, which runs on its own thread.
+    fn serve(source: &str, script: impl FnOnce(&mut Client) + Send + 'static) {
+        let intermediate = Parcher::new().parchSource(source, "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        let machine = program.start().unwrap();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream };
+            script(&mut client);
+        });
+        GdbStub::accept(&mut program, machine, &listener).unwrap().serve().unwrap();
+        client.join().unwrap();
+    }
+
+    fn register(value: u64) -> String {
+        encodeHex(&value.to_le_bytes())
+    }
+
+    #[test]
+    fn scriptedSession() {
+        let source = "main:\nset r1 3\nloop:\ndec r1\neq r1 0\njnz loop\nstore 5 42\nset r8 0\nint 7";
+        serve(source, |client| {
+            let supported = client.request("qSupported:swbreak+");
+            assert!(supported.contains("qXfer:features:read+"));
+            assert!(supported.contains("swbreak+"));
+            assert_eq!(client.request("?"), "S05");
+            let xml = client.request("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+            assert!(xml.contains("<reg name=\"ip\" bitsize=\"64\" type=\"code_ptr\" regnum=\"19\" generic=\"pc\"/>"));
+
+            let registers = client.request("g");
+            assert_eq!(registers.len(), registerCount * 16 + 8);
+            assert_eq!(client.request("p13"), register(0));
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p13"), register(1));
+            assert_eq!(client.request("p1"), register(3));
+            assert_eq!(&client.request("g")[16..32], register(3));
+
+            assert_eq!(client.request("Z0,2,1"), "OK");
+            assert_eq!(client.request("c"), "T05swbreak:;");
+            assert_eq!(client.request("p13"), register(2));
+            assert_eq!(client.request("p1"), register(2));
+            assert_eq!(client.request("c"), "T05swbreak:;");
+            assert_eq!(client.request("p1"), register(1));
+            assert_eq!(client.request("z0,2,1"), "OK");
+
+            assert_eq!(client.request("P1=0100000000000000"), "OK");
+            assert_eq!(client.request("p1"), register(1));
+            assert_eq!(client.request("M8,2:cdab"), "OK");
+            assert_eq!(client.request("m8,2"), "cdab");
+            assert_eq!(client.request("m10,8"), "0000000000000000");
+            assert_eq!(client.request("mffffffffff,8"), "E01");
+
+            assert_eq!(client.request("Z0,4,1"), "OK");
+            assert_eq!(client.request("c"), "T05swbreak:;");
+            assert_eq!(client.request("p13"), register(4));
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("m28,8"), encodeHex(&42u64.to_le_bytes()));
+            assert_eq!(client.request("c"), "W07");
+            assert_eq!(client.request("D"), "OK");
+        });
+    }
+}
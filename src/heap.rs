@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use crate::fault::VmFault;
+use crate::object::{readCount, writeCount};
+
+/// Counters reported by the heap statistics interrupt.
+#[derive(Debug, Clone, Default)]
+pub struct HeapStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub reallocations: usize,
+    pub usedWords: usize,
+    pub usedBlocks: usize,
+    pub freeWords: usize,
+    pub freeBlocks: usize,
+    pub peakWords: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} words in {} blocks, {} free words in {} blocks, peak {} words, {} allocs, {} frees, {} reallocs",
+            self.usedWords,
+            self.usedBlocks,
+            self.freeWords,
+            self.freeBlocks,
+            self.peakWords,
+            self.allocations,
+            self.frees,
+            self.reallocations
+        )
+    }
+}
+
+/// First-fit free list allocator over the part of `mem` after the stack.
+/// Block sizes are kept here rather than in headers inside `mem`, so a program
+/// can't corrupt them. Free neighbours are merged, and a free block at the end
+/// of memory is handed back by shrinking `mem`.
+///
+/// Addresses and sizes taken and returned are in the program's address units,
+/// `wordSize` per word, and blocks are always whole words.
+///
+/// In checked mode freed blocks are never handed back, so freeing one again is
+/// reported as a double free and touching one as a use after free.
+///
+/// With a `limit`, an allocation that would take the live blocks past that many
+/// words faults instead.
+#[derive(Clone)]
+pub struct Heap {
+    checked: bool,
+    wordSize: u64,
+    limit: Option<usize>,
+    /// Live blocks by address, with their size in words.
+    blocks: BTreeMap<usize, usize>,
+    /// Free blocks by address, never adjacent to each other.
+    free: BTreeMap<usize, usize>,
+    stats: HeapStats,
+}
+
+impl Heap {
+    pub fn new(checked: bool, wordSize: u64, limit: Option<usize>) -> Heap {
+        Heap {
+            checked,
+            wordSize,
+            limit,
+            blocks: BTreeMap::new(),
+            free: BTreeMap::new(),
+            stats: HeapStats::default(),
+        }
+    }
+
+    pub fn wordSize(&self) -> u64 {
+        self.wordSize
+    }
+
+    /// Allocates `size` zeroed units, rounded up to whole words, and returns
+    /// the address of the first.
+    pub fn allocate(&mut self, mem: &mut Vec<u64>, size: u64, ip: usize) -> Result<u64, VmFault> {
+        let size = size.div_ceil(self.wordSize) as usize;
+        self.checkLimit(size, 0, ip)?;
+        Ok(self.allocateWords(mem, size) as u64 * self.wordSize)
+    }
+
+    /// Returns the block at `address` to the free list.
+    pub fn free(&mut self, mem: &mut Vec<u64>, address: u64, ip: usize) -> Result<(), VmFault> {
+        let start = self.takeBlock(address, ip)?;
+        self.stats.frees += 1;
+        let size = self.blocks.remove(&start).unwrap();
+        self.stats.usedWords -= size;
+        self.release(mem, start, size);
+        Ok(())
+    }
+
+    /// Resizes the block at `address` to `size` units, moving it when it can't
+    /// grow in place, and returns its new address. Address 0 is never a heap
+    /// block and allocates a new one.
+    pub fn reallocate(&mut self, mem: &mut Vec<u64>, address: u64, size: u64, ip: usize) -> Result<u64, VmFault> {
+        if address == 0 {
+            return self.allocate(mem, size, ip);
+        }
+        let start = self.takeBlock(address, ip)?;
+        let size = size.div_ceil(self.wordSize) as usize;
+        self.checkLimit(size, self.blocks[&start], ip)?;
+        self.stats.reallocations += 1;
+        let moved = self.reallocateWords(mem, start, size);
+        Ok(moved as u64 * self.wordSize)
+    }
+
+    /// In checked mode, faults when `word` lies in a freed block. `address` is
+    /// the address the program used, for the fault.
+    pub fn checkAccess(&self, word: u64, address: u64, ip: usize) -> Result<(), VmFault> {
+        if self.checked && self.freeBlockAt(word as usize).is_some() {
+            return Err(VmFault::UseAfterFree { ip, address });
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            usedBlocks: self.blocks.len(),
+            freeWords: self.free.values().sum(),
+            freeBlocks: self.free.len(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Faults when replacing a live block of `old` words with one of `size`
+    /// words would go over the limit.
+    fn checkLimit(&self, size: usize, old: usize, ip: usize) -> Result<(), VmFault> {
+        let requested = self.stats.usedWords - old + size.max(1);
+        match self.limit {
+            Some(limit) if requested > limit => Err(VmFault::MemoryLimit { ip, requested, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the blocks and counters for a snapshot. The mode and limit come
+    /// from the run's settings instead.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        for map in [&self.blocks, &self.free] {
+            writeCount(writer, map.len())?;
+            for (&address, &size) in map {
+                writeCount(writer, address)?;
+                writeCount(writer, size)?;
+            }
+        }
+        let stats = &self.stats;
+        for count in [stats.allocations, stats.frees, stats.reallocations, stats.usedWords, stats.peakWords] {
+            writeCount(writer, count)?;
+        }
+        Ok(())
+    }
+
+    /// Reads what `write` wrote into an empty heap.
+    pub fn read(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        for map in [&mut self.blocks, &mut self.free] {
+            for _ in 0..readCount(reader)? {
+                let address = readCount(reader)?;
+                map.insert(address, readCount(reader)?);
+            }
+        }
+        let stats = &mut self.stats;
+        for count in [&mut stats.allocations, &mut stats.frees, &mut stats.reallocations, &mut stats.usedWords, &mut stats.peakWords] {
+            *count = readCount(reader)?;
+        }
+        Ok(())
+    }
+
+    /// Empty requests still get a word so every block has its own address.
+    /// In checked mode new blocks always go at the end, past every freed one.
+    fn allocateWords(&mut self, mem: &mut Vec<u64>, size: usize) -> usize {
+        let size = size.max(1);
+        let reusable = self.free.iter().find(|&(_, &free)| !self.checked && free >= size).map(|(&a, &s)| (a, s));
+        let address = match reusable {
+            Some((address, free)) => {
+                self.free.remove(&address);
+                if free > size {
+                    self.free.insert(address + size, free - size);
+                }
+                mem[address..address + size].fill(0);
+                address
+            }
+            None => {
+                // Grow into a free block at the end of memory before appending.
+                let address = match self.free.last_key_value() {
+                    Some((&last, &free)) if !self.checked && last + free == mem.len() => {
+                        self.free.remove(&last);
+                        last
+                    }
+                    _ => mem.len(),
+                };
+                mem[address..].fill(0);
+                mem.resize(address + size, 0);
+                address
+            }
+        };
+        self.blocks.insert(address, size);
+        self.stats.allocations += 1;
+        self.stats.usedWords += size;
+        self.stats.peakWords = self.stats.peakWords.max(self.stats.usedWords);
+        address
+    }
+
+    /// Resizes the live block at word `start`, which must already be out of
+    /// `blocks`, and returns its new word address.
+    fn reallocateWords(&mut self, mem: &mut Vec<u64>, start: usize, size: usize) -> usize {
+        let size = size.max(1);
+        let old = self.blocks.remove(&start).unwrap();
+        self.stats.usedWords -= old;
+        if size <= old {
+            self.release(mem, start + size, old - size);
+        } else if start + old == mem.len() {
+            mem.resize(start + size, 0);
+        } else if let Some(&next) = self.free.get(&(start + old)).filter(|&&next| !self.checked && old + next >= size) {
+            self.free.remove(&(start + old));
+            if old + next > size {
+                self.free.insert(start + size, old + next - size);
+            }
+            mem[start + old..start + size].fill(0);
+        } else {
+            // The old block is out of both maps, so the new one can't overlap it.
+            let moved = self.allocateWords(mem, size);
+            self.stats.allocations -= 1;
+            mem.copy_within(start..start + old, moved);
+            self.release(mem, start, old);
+            return moved;
+        }
+        self.blocks.insert(start, size);
+        self.stats.usedWords += size;
+        self.stats.peakWords = self.stats.peakWords.max(self.stats.usedWords);
+        start
+    }
+
+    /// Word address of the live block at `address`, faulting when there is none.
+    fn takeBlock(&self, address: u64, ip: usize) -> Result<usize, VmFault> {
+        let start = (address / self.wordSize) as usize;
+        if address.is_multiple_of(self.wordSize) && self.blocks.contains_key(&start) {
+            return Ok(start);
+        }
+        if self.checked && self.freeBlockAt(start).is_some() {
+            return Err(VmFault::DoubleFree { ip, address });
+        }
+        Err(VmFault::InvalidFree { ip, address })
+    }
+
+    fn freeBlockAt(&self, word: usize) -> Option<usize> {
+        self.free
+            .range(..=word)
+            .next_back()
+            .filter(|&(&start, &size)| word < start + size)
+            .map(|(&start, _)| start)
+    }
+
+    /// Adds `size` words at `start` to the free list, merging with free neighbours.
+    fn release(&mut self, mem: &mut Vec<u64>, start: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let (mut start, mut size) = (start, size);
+        if let Some((&previous, &previousSize)) = self.free.range(..start).next_back()
+            && previous + previousSize == start
+        {
+            self.free.remove(&previous);
+            start = previous;
+            size += previousSize;
+        }
+        if let Some(next) = self.free.remove(&(start + size)) {
+            size += next;
+        }
+        if !self.checked && start + size == mem.len() {
+            mem.truncate(start);
+        } else {
+            self.free.insert(start, size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freedBlocksAreReused() {
+        let mut heap = Heap::new(false, 1, None);
+        let mut mem = vec![0; 4];
+        let first = heap.allocate(&mut mem, 2, 0).unwrap();
+        heap.allocate(&mut mem, 1, 0).unwrap();
+        heap.free(&mut mem, first, 0).unwrap();
+        assert_eq!(heap.allocate(&mut mem, 2, 0).unwrap(), first);
+    }
+
+    #[test]
+    fn checkedModeNeverReusesFreedBlocks() {
+        let mut heap = Heap::new(true, 1, None);
+        let mut mem = vec![0; 4];
+        let first = heap.allocate(&mut mem, 2, 0).unwrap();
+        heap.free(&mut mem, first, 0).unwrap();
+        let second = heap.allocate(&mut mem, 2, 0).unwrap();
+        assert_ne!(second, first);
+        assert_eq!(heap.free(&mut mem, first, 7), Err(VmFault::DoubleFree { ip: 7, address: first }));
+        assert_eq!(heap.checkAccess(first, first, 8), Err(VmFault::UseAfterFree { ip: 8, address: first }));
+        heap.free(&mut mem, second, 0).unwrap();
+    }
+
+    #[test]
+    fn checkedModeDoesNotGrowIntoFreedBlocks() {
+        let mut heap = Heap::new(true, 1, None);
+        let mut mem = vec![0; 4];
+        let first = heap.allocate(&mut mem, 1, 0).unwrap();
+        let second = heap.allocate(&mut mem, 1, 0).unwrap();
+        heap.free(&mut mem, second, 0).unwrap();
+        let grown = heap.reallocate(&mut mem, first, 2, 0).unwrap();
+        assert!(grown > second);
+        assert_eq!(heap.free(&mut mem, second, 0), Err(VmFault::DoubleFree { ip: 0, address: second }));
+    }
+}
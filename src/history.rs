@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use crate::backends::{ByteCodeCompiler, Flags};
+use crate::fault::VmFault;
+use crate::heap::Heap;
+use crate::machine::Machine;
+use crate::registerCount;
+
+/// What one instruction changed, holding the values from before it so it can
+/// be undone.
+pub struct Record {
+    /// Address of the instruction.
+    pub ip: usize,
+    /// Registers the instruction changed, with their old values.
+    pub registers: Vec<(usize, u64)>,
+    /// Words of `mem` the instruction wrote, with their old values.
+    pub memory: Vec<(usize, u64)>,
+    /// All of `mem` and the heap, kept instead of `memory` for heap interrupts.
+    heap: Option<Box<(Vec<u64>, Heap)>>,
+    carrierbit: bool,
+    flags: Flags,
+    /// Length of the hidden call stack and its top before the instruction.
+    callStack: (usize, Option<usize>),
+    callDepth: usize,
+    executed: u64,
+    /// Input position, output position and read count of the console.
+    console: (u64, u64, usize),
+}
+
+impl Record {
+    pub fn changedRegister(&self, register: usize) -> bool {
+        self.registers.iter().any(|&(changed, _)| changed == register)
+    }
+
+    /// Whether the instruction wrote `word`. `machine` is the state just after
+    /// it, which heap interrupts are compared against.
+    pub fn wroteWord(&self, machine: &Machine, word: usize) -> bool {
+        match &self.heap {
+            Some(before) => before.0.get(word) != machine.mem.get(word),
+            None => self.memory.iter().any(|&(written, _)| written == word),
+        }
+    }
+
+    fn undo(self, machine: &mut Machine) {
+        if let Some(before) = self.heap {
+            (machine.mem, machine.heap) = *before;
+        }
+        for &(word, value) in self.memory.iter().rev() {
+            machine.mem[word] = value;
+        }
+        for (register, value) in self.registers {
+            machine.registers[register] = value;
+        }
+        machine.carrierbit = self.carrierbit;
+        machine.flags = self.flags;
+        let (length, top) = self.callStack;
+        machine.callStack.truncate(length);
+        if machine.callStack.len() < length {
+            machine.callStack.extend(top);
+        }
+        machine.callDepth = self.callDepth;
+        machine.executed = self.executed;
+        let console = &mut machine.console;
+        (console.inputPosition, console.outputPosition, console.readCount) = self.console;
+        machine.exitCode = None;
+        machine.ip = self.ip;
+    }
+}
+
+/// The most recent instructions of a run, newest last, for stepping backwards.
+/// Holds at most `capacity` records, forgetting the oldest.
+pub struct History {
+    records: VecDeque<Record>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History { records: VecDeque::new(), capacity }
+    }
+
+    /// The instruction `stepBack` would undo next.
+    pub fn last(&self) -> Option<&Record> {
+        self.records.back()
+    }
+
+    /// Undoes the newest recorded instruction, returning false when there is none.
+    pub fn stepBack(&mut self, machine: &mut Machine) -> bool {
+        match self.records.pop_back() {
+            Some(record) => {
+                record.undo(machine);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn push(&mut self, record: Record) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+impl ByteCodeCompiler {
+    /// Like `step`, recording what the instruction changes in `history`. An
+    /// instruction that faults is undone straight away, so the machine is
+    /// left as it was before it.
+    pub fn recordedStep(&mut self, machine: &mut Machine, history: &mut History) -> Result<(), VmFault> {
+        let written = self.writtenWords(machine);
+        let registers = machine.registers;
+        let console = &machine.console;
+        let mut record = Record {
+            ip: machine.ip,
+            registers: vec![],
+            memory: written
+                .iter()
+                .flatten()
+                .filter_map(|&word| machine.mem.get(word).map(|&value| (word, value)))
+                .collect(),
+            heap: written.is_none().then(|| Box::new((machine.mem.clone(), machine.heap.clone()))),
+            carrierbit: machine.carrierbit,
+            flags: machine.flags,
+            callStack: (machine.callStack.len(), machine.callStack.last().copied()),
+            callDepth: machine.callDepth,
+            executed: machine.executed,
+            console: (console.inputPosition, console.outputPosition, console.readCount),
+        };
+        let result = self.step(machine);
+        record.registers = (0..registerCount)
+            .filter(|&register| machine.registers[register] != registers[register])
+            .map(|register| (register, registers[register]))
+            .collect();
+        match result {
+            Ok(()) => history.push(record),
+            Err(_) => record.undo(machine),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::{resultRegister, Parcher};
+
+    fn compile(source: &str) -> ByteCodeCompiler {
+        let intermediate = Parcher::new().parchSource(source, "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        program
+    }
+
+    /// Everything undoing an instruction has to put back.
+    fn state(machine: &Machine) -> String {
+        let mut heap = vec![];
+        machine.heap.write(&mut heap).unwrap();
+        let console = &machine.console;
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {} {} {} {} {:?} {:?}",
+            machine.registers,
+            machine.mem,
+            heap,
+            machine.callStack,
+            machine.flags,
+            machine.carrierbit,
+            machine.ip,
+            machine.executed,
+            machine.callDepth,
+            (console.inputPosition, console.outputPosition, console.readCount),
+            machine.exitCode
+        )
+    }
+
+    #[test]
+    fn steppingBackUndoesEverything() {
+        let source = "main:\npush 7\ncall f\nset r8 1\nint 3\nstore r7 99\nset r8 11\nint 0\nmov r1 r7\n\
+                      int 0\nset r8 3\nint r1\ncmp r1 1\npop r2\nhlt\nf:\neq 1 1\nret";
+        let mut program = compile(source);
+        let mut machine = program.start().unwrap();
+        machine.console.input = Some(Box::new(io::Cursor::new(b"xy".to_vec())));
+        machine.console.output = Some(Box::new(io::sink()));
+        let start = state(&machine);
+        let mut history = History::new(100);
+        let mut states = vec![];
+        while program.isRunning(&machine) {
+            program.recordedStep(&mut machine, &mut history).unwrap();
+            states.push(state(&machine));
+        }
+        assert_eq!(machine.registers[1], 'x' as u64);
+        assert_eq!(machine.registers[resultRegister], 'y' as u64);
+        assert_eq!(machine.registers[2], 7);
+
+        while history.stepBack(&mut machine) {}
+        assert_eq!(state(&machine), start);
+
+        // The reads replay what they returned the first time, whatever the input is now.
+        machine.console.input = Some(Box::new(io::Cursor::new(b"zz".to_vec())));
+        for expected in &states {
+            program.recordedStep(&mut machine, &mut history).unwrap();
+            assert_eq!(&state(&machine), expected);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::fs;
+
+/// What an instruction accepts in one operand position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    /// No operand may be written.
+    None,
+    /// A register that the instruction writes or reads in place.
+    Register,
+    /// A register or an immediate value, which may be a label's address.
+    Value,
+    /// A jump target: a label or a register holding the address.
+    Target,
+    /// A memory address: an immediate, a register or a `[...]` memory operand.
+    Address,
+}
+
+/// Section of the instruction reference an instruction is listed in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Group {
+    DataMovement,
+    Arithmetic,
+    Bitwise,
+    ControlFlow,
+    Comparison,
+    FloatingPoint,
+    System,
+}
+
+impl Group {
+    const all: [Group; 7] = [
+        Group::DataMovement,
+        Group::Arithmetic,
+        Group::Bitwise,
+        Group::ControlFlow,
+        Group::Comparison,
+        Group::FloatingPoint,
+        Group::System,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            Group::DataMovement => "Data Movement",
+            Group::Arithmetic => "Arithmetic",
+            Group::Bitwise => "Bitwise",
+            Group::ControlFlow => "Control Flow",
+            Group::Comparison => "Comparison",
+            Group::FloatingPoint => "Floating Point",
+            Group::System => "System",
+        }
+    }
+
+    /// Shown above the group's table in the reference.
+    fn note(self) -> Option<&'static str> {
+        match self {
+            Group::Arithmetic => Some("Arithmetic instructions update the flags register and wrap around on overflow."),
+            Group::Bitwise => Some("Bitwise instructions update the flags register. Shift and rotate amounts are taken modulo 64."),
+            Group::FloatingPoint => Some(
+                "Float instructions read registers as `f64` bit patterns and leave the flags register alone. \
+                 Any comparison with NaN is false, except `fneq`, which is true.",
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Everything the assembler, disassembler, VM and reference need to know about
+/// one instruction.
+#[derive(Debug)]
+pub struct InstructionSpec {
+    pub mnemonic: &'static str,
+    pub opcode: u8,
+    /// Kinds of the first and second operand.
+    pub operands: (OperandKind, OperandKind),
+    pub group: Group,
+    /// Execution never continues with the next instruction.
+    pub endsBlock: bool,
+    /// What the instruction does, with code in backticks.
+    pub description: &'static str,
+}
+
+/// Defines a constant per opcode, for the VM's dispatch, and the table of
+/// specs, in opcode order.
+macro_rules! instructions {
+    ($($name:ident $mnemonic:literal = $opcode:literal, ($first:ident, $second:ident), $group:ident, $endsBlock:literal, $description:literal;)*) => {
+        $(pub const $name: u8 = $opcode;)*
+
+        pub static instructionTable: &[InstructionSpec] = &[$(InstructionSpec {
+            mnemonic: $mnemonic,
+            opcode: $opcode,
+            operands: (OperandKind::$first, OperandKind::$second),
+            group: Group::$group,
+            endsBlock: $endsBlock,
+            description: $description,
+        },)*];
+    };
+}
+
+instructions! {
+    mov "mov" = 1, (Register, Value), DataMovement, false, "Copies `rY` or `value` into `rX`. Same as `set`.";
+    add "add" = 2, (Register, Value), Arithmetic, false, "Adds `rY` or `value` to `rX`.";
+    sub "sub" = 3, (Register, Value), Arithmetic, false, "Subtracts `rY` or `value` from `rX`.";
+    div "div" = 4, (Register, Value), Arithmetic, false, "Divides `rX` by `rY` or `value`, unsigned. Dividing by zero is a fault.";
+    mul "mul" = 5, (Register, Value), Arithmetic, false, "Multiplies `rX` by `rY` or `value`.";
+    and "and" = 6, (Register, Value), Bitwise, false, "Bitwise AND.";
+    or "or" = 7, (Register, Value), Bitwise, false, "Bitwise OR.";
+    xor "xor" = 8, (Register, Value), Bitwise, false, "Bitwise XOR.";
+    shr "shr" = 9, (Register, Value), Bitwise, false, "Shift right, filling with zeros.";
+    shl "shl" = 10, (Register, Value), Bitwise, false, "Shift left.";
+    store "store" = 11, (Address, Value), DataMovement, false, "Stores `rY` or `value` at address `addr`, `rX` or `[mem]`. A word address, or a byte address with `--byte-addressing`.";
+    load "load" = 12, (Register, Address), DataMovement, false, "Loads the word at address `addr`, `rY` or `[mem]` into `rX`. A word address, or a byte address with `--byte-addressing`.";
+    push "push" = 13, (Value, None), DataMovement, false, "Pushes `rX` or `value` onto the stack.";
+    pop "pop" = 14, (Register, None), DataMovement, false, "Pops from the stack into `rX`.";
+    jmp "jmp" = 15, (Target, None), ControlFlow, true, "Jumps unconditionally.";
+    jz "jz" = 16, (Target, None), ControlFlow, false, "Jumps if the carrierbit is set, i.e. the last `eq`, `neq`, `big`, `sm`, `bigs`, `sms` or float comparison was true. It does not read the zero flag.";
+    jnz "jnz" = 17, (Target, None), ControlFlow, false, "Jumps if the carrierbit is clear, i.e. the last comparison was false.";
+    eq "eq" = 18, (Value, Value), Comparison, false, "Sets the carrierbit if the operands are equal.";
+    neq "neq" = 19, (Value, Value), Comparison, false, "Sets the carrierbit if the operands are not equal.";
+    big "big" = 20, (Value, Value), Comparison, false, "Sets the carrierbit if the first operand is greater, unsigned.";
+    sm "sm" = 21, (Value, Value), Comparison, false, "Sets the carrierbit if the first operand is smaller, unsigned.";
+    hlt "hlt" = 22, (None, None), System, true, "Stops the program with exit code 0, whatever is in `r8`.";
+    int "int" = 23, (Value, None), System, false, "Runs the interrupt whose code is in `r8`, with `rX` or `value` as its argument. Interrupts that return a value write it to `r7`.";
+    set "set" = 24, (Register, Value), DataMovement, false, "Copies `rY` or `value` into `rX`. Same as `mov`.";
+    call "call" = 25, (Target, None), ControlFlow, false, "Calls a subroutine. The return address goes to `lr` and, unless running with `--link-calls`, onto the call stack, which is the `sp` stack with `--stack-calls`.";
+    ret "ret" = 26, (None, None), ControlFlow, true, "Returns from a subroutine to the return address popped from the call stack, from the `sp` stack with `--stack-calls`, or in `lr` with `--link-calls`.";
+    bigs "bigs" = 27, (Value, Value), Comparison, false, "Sets the carrierbit if the first operand is greater, signed.";
+    sms "sms" = 28, (Value, Value), Comparison, false, "Sets the carrierbit if the first operand is smaller, signed.";
+    divs "divs" = 29, (Register, Value), Arithmetic, false, "Divides `rX` by `rY` or `value`, signed, rounding toward zero. Dividing by zero is a fault.";
+    mods "mods" = 30, (Register, Value), Arithmetic, false, "Signed remainder of `rX` divided by `rY` or `value`. The result has the sign of `rX`. A zero divisor is a fault.";
+    sar "sar" = 31, (Register, Value), Bitwise, false, "Arithmetic shift right, filling with the sign bit.";
+    r#mod "mod" = 32, (Register, Value), Arithmetic, false, "Unsigned remainder of `rX` divided by `rY` or `value`. A zero divisor is a fault.";
+    not "not" = 33, (Register, None), Bitwise, false, "Bitwise NOT.";
+    neg "neg" = 34, (Register, None), Arithmetic, false, "Negates `rX` (two's complement).";
+    inc "inc" = 35, (Register, None), Arithmetic, false, "Adds 1 to `rX`.";
+    dec "dec" = 36, (Register, None), Arithmetic, false, "Subtracts 1 from `rX`.";
+    rol "rol" = 37, (Register, Value), Bitwise, false, "Rotate left.";
+    ror "ror" = 38, (Register, Value), Bitwise, false, "Rotate right.";
+    min "min" = 39, (Register, Value), Arithmetic, false, "Sets `rX` to the smaller of `rX` and `rY` or `value`, unsigned.";
+    max "max" = 40, (Register, Value), Arithmetic, false, "Sets `rX` to the larger of `rX` and `rY` or `value`, unsigned.";
+    cmp "cmp" = 41, (Value, Value), Comparison, false, "Sets the flags as `sub` would, without writing a register. Use with the conditional jumps.";
+    je "je" = 42, (Target, None), ControlFlow, false, "Jumps if the zero flag is set (equal).";
+    jne "jne" = 43, (Target, None), ControlFlow, false, "Jumps if the zero flag is clear (not equal).";
+    jl "jl" = 44, (Target, None), ControlFlow, false, "Jumps if signed less: N ≠ V.";
+    jg "jg" = 45, (Target, None), ControlFlow, false, "Jumps if signed greater: Z clear and N = V.";
+    jle "jle" = 46, (Target, None), ControlFlow, false, "Jumps if signed less or equal: Z set or N ≠ V.";
+    jge "jge" = 47, (Target, None), ControlFlow, false, "Jumps if signed greater or equal: N = V.";
+    jb "jb" = 48, (Target, None), ControlFlow, false, "Jumps if unsigned below: C set.";
+    ja "ja" = 49, (Target, None), ControlFlow, false, "Jumps if unsigned above: C and Z clear.";
+    jc "jc" = 50, (Target, None), ControlFlow, false, "Jumps if the carry flag is set.";
+    jnc "jnc" = 51, (Target, None), ControlFlow, false, "Jumps if the carry flag is clear.";
+    jo "jo" = 52, (Target, None), ControlFlow, false, "Jumps if the overflow flag is set.";
+    jno "jno" = 53, (Target, None), ControlFlow, false, "Jumps if the overflow flag is clear.";
+    js "js" = 54, (Target, None), ControlFlow, false, "Jumps if the negative flag is set.";
+    jns "jns" = 55, (Target, None), ControlFlow, false, "Jumps if the negative flag is clear.";
+    enter "enter" = 56, (Value, None), DataMovement, false, "Sets up a stack frame: pushes `fp`, sets `fp` to `sp`, then reserves `rX` or `value` words for locals above `fp`.";
+    leave "leave" = 57, (None, None), DataMovement, false, "Tears down the frame made by `enter`: sets `sp` to `fp` and pops the saved `fp`.";
+    load8 "load8" = 58, (Register, Address), DataMovement, false, "Loads 1 byte from a byte address into `rX`, zero-extended.";
+    load16 "load16" = 59, (Register, Address), DataMovement, false, "Loads 2 bytes from a byte address into `rX`, little-endian and zero-extended.";
+    load32 "load32" = 60, (Register, Address), DataMovement, false, "Loads 4 bytes from a byte address into `rX`, little-endian and zero-extended.";
+    load64 "load64" = 61, (Register, Address), DataMovement, false, "Loads 8 bytes from a byte address into `rX`, little-endian.";
+    store8 "store8" = 62, (Address, Value), DataMovement, false, "Stores the low byte of `rY` or `value` at a byte address.";
+    store16 "store16" = 63, (Address, Value), DataMovement, false, "Stores the low 2 bytes of `rY` or `value` at a byte address, little-endian.";
+    store32 "store32" = 64, (Address, Value), DataMovement, false, "Stores the low 4 bytes of `rY` or `value` at a byte address, little-endian.";
+    store64 "store64" = 65, (Address, Value), DataMovement, false, "Stores `rY` or `value` as 8 bytes at a byte address, little-endian.";
+    fadd "fadd" = 66, (Register, Value), FloatingPoint, false, "`rX` = `rX` + `rY` as floats.";
+    fsub "fsub" = 67, (Register, Value), FloatingPoint, false, "`rX` = `rX` - `rY` as floats.";
+    fmul "fmul" = 68, (Register, Value), FloatingPoint, false, "`rX` = `rX` * `rY` as floats.";
+    fdiv "fdiv" = 69, (Register, Value), FloatingPoint, false, "`rX` = `rX` / `rY` as floats. Dividing by zero gives an infinity or NaN rather than stopping.";
+    fsqrt "fsqrt" = 70, (Register, None), FloatingPoint, false, "`rX` = the square root of `rX`. Negative values give NaN.";
+    feq "feq" = 71, (Value, Value), FloatingPoint, false, "Sets the carrierbit if the floats are equal.";
+    fneq "fneq" = 72, (Value, Value), FloatingPoint, false, "Sets the carrierbit if the floats are not equal.";
+    fbig "fbig" = 73, (Value, Value), FloatingPoint, false, "Sets the carrierbit if the first float is greater.";
+    fsm "fsm" = 74, (Value, Value), FloatingPoint, false, "Sets the carrierbit if the first float is smaller.";
+    itof "itof" = 75, (Register, None), FloatingPoint, false, "Converts the signed integer in `rX` to a float.";
+    ftoi "ftoi" = 76, (Register, None), FloatingPoint, false, "Converts the float in `rX` to a signed integer, rounding toward zero. Values out of range saturate, and NaN becomes 0.";
+}
+
+pub fn byMnemonic(mnemonic: &str) -> Option<&'static InstructionSpec> {
+    instructionTable.iter().find(|spec| spec.mnemonic == mnemonic)
+}
+
+pub fn byOpcode(opcode: u8) -> Option<&'static InstructionSpec> {
+    instructionTable.iter().find(|spec| spec.opcode == opcode)
+}
+
+/// Mnemonic of `opcode`, for diagnostics about opcodes that may be invalid.
+pub fn mnemonicOf(opcode: u8) -> String {
+    byOpcode(opcode).map_or(format!("<opcode {}>", opcode), |spec| spec.mnemonic.to_string())
+}
+
+/// Marks the generated instruction reference in the HTML docs.
+const referenceBegin: &str = "<!-- instruction reference: generated by `nis --reference`, do not edit -->";
+const referenceEnd: &str = "<!-- end of instruction reference -->";
+
+/// How one operand is written in the reference's usage column.
+fn operandUsage(kind: OperandKind, register: &str) -> String {
+    match kind {
+        OperandKind::None => String::new(),
+        OperandKind::Register => register.to_string(),
+        OperandKind::Value => format!("{}/value", register),
+        OperandKind::Target => format!("label/{}", register),
+        OperandKind::Address => format!("addr/{}/[mem]", register),
+    }
+}
+
+fn usage(spec: &InstructionSpec) -> String {
+    [spec.mnemonic.to_string(), operandUsage(spec.operands.0, "rX"), operandUsage(spec.operands.1, "rY")]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escapes `text` for HTML and turns `code` spans into `<code>` elements.
+fn html(text: &str) -> String {
+    let escaped = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    escaped
+        .split('`')
+        .enumerate()
+        .map(|(index, part)| if index % 2 == 1 { format!("<code>{}</code>", part) } else { part.to_string() })
+        .collect()
+}
+
+/// The instruction reference as HTML: one table per group, in opcode order.
+pub fn referenceHtml() -> String {
+    let mut output = String::new();
+    for group in Group::all {
+        output += &format!("<h3>{}</h3>\n", group.title());
+        if let Some(note) = group.note() {
+            output += &format!("<p>{}</p>\n", html(note));
+        }
+        output += "<table>\n    <thead>\n    <tr>\n        <th>Instruction</th>\n        <th>Opcode</th>\n        <th>Usage</th>\n        <th>Description</th>\n    </tr>\n    </thead>\n    <tbody>\n";
+        for spec in instructionTable.iter().filter(|spec| spec.group == group) {
+            output += &format!(
+                "    <tr>\n        <td><code>{}</code></td>\n        <td>{}</td>\n        <td><code>{}</code></td>\n        <td>{}</td>\n    </tr>\n",
+                spec.mnemonic,
+                spec.opcode,
+                html(&usage(spec)),
+                html(spec.description)
+            );
+        }
+        output += "    </tbody>\n</table>\n\n";
+    }
+    output
+}
+
+/// Replaces the generated instruction reference in the HTML file at `path`.
+pub fn writeReference(path: &str) -> Result<(), String> {
+    let document = fs::read_to_string(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+    let (Some(begin), Some(end)) = (document.find(referenceBegin), document.find(referenceEnd)) else {
+        return Err(format!("{} has no generated instruction reference markers", path));
+    };
+    let updated = format!("{}\n{}\n{}", &document[..begin + referenceBegin.len()], referenceHtml().trim_end(), &document[end..]);
+    fs::write(path, updated).map_err(|error| format!("could not write {}: {}", path, error))
+}
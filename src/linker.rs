@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use crate::archive::Archive;
+use crate::backends::{ByteCodeCompiler, Relocation};
+use crate::object::ObjectFile;
+
+/// Combines relocatable objects into one executable image. Objects are placed
+/// one after another in the order they were added, followed by the archive
+/// members that were pulled in.
+pub struct Linker {
+    pub objects: Vec<(String, ObjectFile)>,
+    pub archives: Vec<(String, Archive)>,
+    /// Entry label chosen on the command line, overrides the objects' `#entry`.
+    pub entryLabel: Option<String>,
+    pub library: bool,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            objects: vec![],
+            archives: vec![],
+            entryLabel: None,
+            library: false,
+        }
+    }
+
+    pub fn addObject(&mut self, name: &str, object: ObjectFile) {
+        self.objects.push((name.to_string(), object));
+    }
+
+    pub fn addArchive(&mut self, name: &str, archive: Archive) {
+        self.archives.push((name.to_string(), archive));
+    }
+
+    /// Picks the archive members that define a symbol which is still undefined,
+    /// repeating until no member resolves anything new, like `ar` libraries.
+    fn pullArchiveMembers(&self) -> Vec<(String, &ObjectFile)> {
+        let mut selected: Vec<(String, &ObjectFile)> = self
+            .objects
+            .iter()
+            .map(|(name, object)| (name.clone(), object))
+            .collect();
+        let mut taken: HashSet<(usize, usize)> = HashSet::new();
+        'search: loop {
+            let defined: HashSet<String> = selected
+                .iter()
+                .flat_map(|(_, object)| object.symbols.iter().map(|(symbol, _)| symbol.clone()))
+                .collect();
+            let mut undefined: HashSet<String> = selected
+                .iter()
+                .flat_map(|(_, object)| object.imports.iter().cloned())
+                .collect();
+            if let Some(entryLabel) = self.entryLabel(&selected) {
+                undefined.insert(entryLabel);
+            }
+            undefined.retain(|symbol| !defined.contains(symbol));
+            for (archiveIndex, (archiveName, archive)) in self.archives.iter().enumerate() {
+                for (memberIndex, (memberName, member)) in archive.members.iter().enumerate() {
+                    if taken.contains(&(archiveIndex, memberIndex))
+                        || !member.symbols.iter().any(|(symbol, _)| undefined.contains(symbol))
+                    {
+                        continue;
+                    }
+                    taken.insert((archiveIndex, memberIndex));
+                    selected.push((format!("{}({})", archiveName, memberName), member));
+                    continue 'search;
+                }
+            }
+            return selected;
+        }
+    }
+
+    fn entryLabel(&self, objects: &[(String, &ObjectFile)]) -> Option<String> {
+        self.entryLabel
+            .clone()
+            .or_else(|| objects.iter().find_map(|(_, object)| object.entryLabel.clone()))
+            .or_else(|| (!self.library).then(|| "main".to_string()))
+    }
+
+    pub fn link(&self) -> Result<ByteCodeCompiler, Vec<String>> {
+        let mut errors = vec![];
+        let mut image = ByteCodeCompiler::new();
+        let mut definedIn: HashMap<String, &str> = HashMap::new();
+        let mut bases = vec![];
+        let objects = self.pullArchiveMembers();
+        for (name, object) in &objects {
+            let base = image.lines.len();
+            bases.push(base);
+            for (symbol, address) in &object.symbols {
+                if let Some(previous) = definedIn.get(symbol) {
+                    errors.push(format!("duplicate symbol `{}` defined in {} and {}", symbol, previous, name));
+                    continue;
+                }
+                definedIn.insert(symbol.clone(), name);
+                image.labels.insert(symbol.clone(), base + address);
+                image.exports.push(symbol.clone());
+            }
+            image.lines.extend(object.lines.iter().cloned());
+            image.sourceMap.extend(object.lines.iter().map(|_| None));
+        }
+
+        for ((name, object), base) in objects.iter().zip(bases) {
+            let own = object.labelsAt(base);
+            for relocation in &object.relocations {
+                let Some(target) = own.get(relocation.symbol.as_str()).or_else(|| image.labels.get(&relocation.symbol)).copied() else {
+                    let error = format!("undefined symbol `{}` referenced from {}", relocation.symbol, name);
+                    if !errors.contains(&error) {
+                        errors.push(error);
+                    }
+                    continue;
+                };
+                relocation.apply(&mut image.lines[base + relocation.index], target);
+                // Kept so a linked library can still be rebased when loaded as a module.
+                image.relocations.push(Relocation { index: base + relocation.index, location: None, ..relocation.clone() });
+            }
+        }
+
+        if let Some(label) = self.entryLabel(&objects) {
+            match image.labels.get(&label) {
+                Some(&entry) => image.entry = Some(entry),
+                None => errors.push(format!("entry label `{}` is not defined in any object", label)),
+            }
+        }
+
+        if errors.is_empty()
+            && let Err(problems) = image.verify()
+        {
+            errors.extend(problems.iter().map(|problem| problem.to_string()));
+        }
+        if errors.is_empty() {
+            Ok(image)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parcher;
+
+    fn object(source: &str, name: &str) -> ObjectFile {
+        let intermediate = Parcher::new().parchSource(source, name).unwrap();
+        let mut compiler = ByteCodeCompiler::new();
+        compiler.object = true;
+        compiler.compileByteCodeFromIntermediate(intermediate).unwrap();
+        ObjectFile::fromCompiler(&compiler)
+    }
+
+    #[test]
+    fn localLabelsStayInTheirObject() {
+        let mut linker = Linker::new();
+        linker.addObject("a.o", object("main:\nloop:\ncall helper\njmp loop", "a.asm"));
+        linker.addObject("b.o", object("#export helper\nhelper:\nloop:\njmp loop\nret", "b.asm"));
+        let image = linker.link().unwrap();
+        assert_eq!(image.entry, Some(0));
+        assert_eq!(image.lines[0].arg1, 2);
+        assert_eq!(image.lines[1].arg1, 0);
+        assert_eq!(image.lines[2].arg1, 2);
+        assert!(!image.labels.contains_key("loop"));
+    }
+
+    #[test]
+    fn unexportedLabelsAreNotVisible() {
+        let mut linker = Linker::new();
+        linker.addObject("a.o", object("main:\ncall helper\nhlt", "a.asm"));
+        linker.addObject("b.o", object("helper:\nret", "b.asm"));
+        assert_eq!(linker.link().err().unwrap(), vec!["undefined symbol `helper` referenced from a.o".to_string()]);
+    }
+
+    #[test]
+    fn exportedLabelsMustBeUnique() {
+        let mut linker = Linker::new();
+        linker.addObject("a.o", object("#export helper\nmain:\nhelper:\nret", "a.asm"));
+        linker.addObject("b.o", object("#export helper\nhelper:\nret", "b.asm"));
+        assert_eq!(linker.link().err().unwrap(), vec!["duplicate symbol `helper` defined in a.o and b.o".to_string()]);
+    }
+
+    #[test]
+    fn archivesContributeOnlyTheMembersThatAreNeeded() {
+        let mut archive = Archive::new();
+        archive.addMember("unused.o", object("#export unused\nunused:\nret", "unused.asm"));
+        archive.addMember("inner.o", object("#export inner\ninner:\nret", "inner.asm"));
+        archive.addMember("outer.o", object("#export outer\nouter:\ncall inner\nret", "outer.asm"));
+        let mut linker = Linker::new();
+        linker.addObject("a.o", object("main:\ncall outer\nhlt", "a.asm"));
+        linker.addArchive("lib.a", archive);
+        let names: Vec<String> = linker.pullArchiveMembers().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["a.o", "lib.a(outer.o)", "lib.a(inner.o)"]);
+        let image = linker.link().unwrap();
+        assert!(!image.labels.contains_key("unused"));
+        assert_eq!(image.labels["outer"], 2);
+        assert_eq!(image.labels["inner"], 4);
+        assert_eq!(image.lines[2].arg1, 4);
+    }
+}
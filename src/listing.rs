@@ -0,0 +1,77 @@
+use std::fs::File;
+use std::io::{self, Write};
+use crate::backends::ByteCodeCompiler;
+use crate::{lineSize, SourceLocation};
+
+/// Width of an encoded `Line` record written as space separated hex pairs.
+const encodedWidth: usize = lineSize * 3 - 1;
+
+/// Writes an assembler listing: every source line next to the index and the
+/// encoded `Line` record it produced, followed by a symbol table of all labels.
+/// Lines that came in through `#include` are marked with `+`.
+pub fn writeListing(
+    path: &str,
+    rootFile: &str,
+    source: &[(String, SourceLocation)],
+    compiler: &ByteCodeCompiler,
+) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(file, "NIS listing of {}", rootFile)?;
+    match compiler.entry {
+        Some(entry) => writeln!(file, "entry point: {}", entry)?,
+        None => writeln!(file, "entry point: none (library)")?,
+    }
+    writeln!(file)?;
+    writeln!(file, "{:>5}  {:<width$}   {:>5}  SOURCE", "INDEX", "ENCODED", "LINE", width = encodedWidth)?;
+
+    let mut index = 0;
+    for (text, location) in source {
+        let marker = if location.file == rootFile { ' ' } else { '+' };
+        if index < compiler.lines.len() && compiler.sourceMap[index].as_ref() == Some(location) {
+            writeln!(
+                file,
+                "{:>5}  {}  {}{:>5}  {}",
+                index,
+                hex(&compiler.lines[index].encode()),
+                marker,
+                location.line,
+                text
+            )?;
+            index += 1;
+        } else {
+            writeln!(file, "{:>5}  {:<width$}  {}{:>5}  {}", "", "", marker, location.line, text, width = encodedWidth)?;
+        }
+    }
+
+    writeln!(file)?;
+    writeln!(file, "RELOCATIONS")?;
+    for relocation in &compiler.relocations {
+        let target = match compiler.labels.get(&relocation.symbol) {
+            Some(address) => address.to_string(),
+            None => "import".to_string(),
+        };
+        writeln!(
+            file,
+            "{:>5}  {:?} -> {} ({})",
+            relocation.index, relocation.operand, relocation.symbol, target
+        )?;
+    }
+
+    writeln!(file)?;
+    writeln!(file, "SYMBOLS")?;
+    let mut symbols: Vec<(&String, &usize)> = compiler.labels.iter().collect();
+    symbols.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+    for (name, address) in symbols {
+        let location = compiler
+            .labelLocations
+            .get(name)
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        writeln!(file, "{:<24} {:>5}  0x{:04x}  {}", name, address, address, location)?;
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(" ")
+}
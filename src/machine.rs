@@ -0,0 +1,123 @@
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+use crate::backends::Flags;
+use crate::heap::Heap;
+use crate::{ipRegister, registerCount};
+
+/// Returned in `r7` by the read interrupt once standard input is exhausted.
+pub const endOfInput: u64 = u64::MAX;
+
+/// Everything a running program can change. `ByteCodeCompiler::start` makes
+/// one at the entry point and `step` advances it, so a debugger or a snapshot
+/// can stop, look at and resume a run between any two instructions.
+pub struct Machine {
+    pub registers: [u64; registerCount],
+    pub mem: Vec<u64>,
+    pub carrierbit: bool,
+    pub flags: Flags,
+    /// Return addresses of the hidden call stack.
+    pub callStack: Vec<usize>,
+    pub heap: Heap,
+    pub ip: usize,
+    /// Instructions executed since the program started, across restores.
+    pub executed: u64,
+    /// Calls that haven't returned yet, for `Limits::maxCallDepth`.
+    pub callDepth: usize,
+    pub console: Console,
+    /// Set once the program exits through interrupt 0 or `hlt`.
+    pub exitCode: Option<i32>,
+    /// Hash of the program the machine was started for, see `programHash`.
+    pub programHash: u64,
+    /// When this run began, for `Limits::timeout`. Not part of a snapshot.
+    pub started: Instant,
+}
+
+impl Machine {
+    /// The value of `register` as the program sees it. `registers[ipRegister]`
+    /// is only brought up to date as an instruction starts, so `ip` comes from
+    /// `self.ip` instead.
+    pub fn register(&self, register: usize) -> u64 {
+        if register == ipRegister { self.ip as u64 } else { self.registers[register] }
+    }
+}
+
+/// The program's standard input and output, counting the bytes that went
+/// through each so a restored run picks up where the input left off.
+///
+/// Every read is kept, so when history rewinds a run the reads it replays
+/// return what they returned the first time, and output that was already
+/// printed isn't printed again.
+#[derive(Default)]
+pub struct Console {
+    /// Where reads come from, standard input when `None`.
+    pub input: Option<Box<dyn Read>>,
+    /// Where output goes, standard output when `None`.
+    pub output: Option<Box<dyn Write>>,
+    pub inputPosition: u64,
+    pub outputPosition: u64,
+    /// Results of the read interrupts so far, `None` for the end of input.
+    pub reads: Vec<Option<u8>>,
+    /// Read interrupts done, an index into `reads`.
+    pub readCount: usize,
+    /// Furthest `outputPosition` ever reached.
+    printed: u64,
+}
+
+impl Console {
+    pub fn print(&mut self, value: impl Display) {
+        let text = value.to_string();
+        if self.outputPosition >= self.printed {
+            match &mut self.output {
+                Some(output) => {
+                    output.write_all(text.as_bytes()).ok();
+                }
+                None => print!("{}", text),
+            }
+        }
+        self.outputPosition += text.len() as u64;
+        self.printed = self.printed.max(self.outputPosition);
+    }
+
+    /// Reads the next byte of standard input, `None` at its end.
+    pub fn readByte(&mut self) -> Option<u8> {
+        if self.readCount == self.reads.len() {
+            // Output written so far is shown before the program waits for input.
+            match &mut self.output {
+                Some(output) => output.flush().ok(),
+                None => io::stdout().flush().ok(),
+            };
+            let mut byte = [0u8; 1];
+            let read = match self.reader().read(&mut byte) {
+                Ok(1) => Some(byte[0]),
+                _ => None,
+            };
+            self.reads.push(read);
+        }
+        let read = self.reads[self.readCount];
+        self.readCount += 1;
+        self.inputPosition += read.is_some() as u64;
+        read
+    }
+
+    /// Consumes the first `inputPosition` bytes of the input, which the run a
+    /// snapshot was taken from has already read.
+    pub fn skipInput(&mut self) -> io::Result<()> {
+        let position = self.inputPosition;
+        let skipped = io::copy(&mut self.reader().take(position), &mut io::sink())?;
+        if skipped < position {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the input ends after {} of the {} bytes already read", skipped, position),
+            ));
+        }
+        Ok(())
+    }
+
+    fn reader(&mut self) -> Box<dyn Read + '_> {
+        match &mut self.input {
+            Some(input) => Box::new(input),
+            None => Box::new(io::stdin().lock()),
+        }
+    }
+}
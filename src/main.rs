@@ -1,28 +1,77 @@
+#![allow(non_snake_case, non_upper_case_globals, non_camel_case_types)]
+mod archive;
 mod backends;
+mod debugger;
+mod disassembler;
+mod fault;
+mod gdbstub;
+mod heap;
+mod history;
+mod instructions;
+mod linker;
+mod listing;
+mod machine;
+mod modules;
+mod object;
+mod snapshot;
+mod verifier;
+mod watch;
 
-use clap::{Arg, ArgAction, Command};
-use std::collections::HashMap;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, Lines, Read, Write};
+use std::io::{self, BufReader, Read};
 use std::path::Path;
-use std::{env, io, process};
-use crate::backends::ByteCodeCompiler;
+use std::process;
+use std::time::Duration;
+use crate::archive::{archiveMagic, Archive};
+use crate::backends::{defaultStackSize, ByteCodeCompiler, CallMode, Limits};
+use crate::debugger::Debugger;
+use crate::fault::VmFault;
+use crate::gdbstub::GdbStub;
+use crate::linker::Linker;
+use crate::machine::Machine;
+use crate::object::ObjectFile;
 
-static stringInstructionsToU8: [&str; 27] = [
-    "", "mov", "add", "sub", "div", "mul", "and", "or", "xor", "shr", "shl", "store", "load",
-    "push", "pop", "jmp", "jz", "jnz", "eq", "neq", "big", "sm", "hlt", "int", "set", "call",
-    "ret",
+/// Register names, indexed by their encoding. The parser, encoder, disassembler
+/// and VM all go through this table, and the VM has one slot per entry.
+/// Registers added later are appended so existing bytecode keeps its meaning.
+static stringToReg: [&str; registerCount] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "sp", "fp", "r9", "r10", "r11", "r12",
+    "r13", "r14", "r15", "lr", "ip",
 ];
-static stringToReg: [&str; 10] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "sp"];
+const registerCount: usize = 20;
+/// Interrupts return their result here.
+const resultRegister: usize = 7;
+/// Interrupts read their code from here.
+const interruptRegister: usize = 8;
+const spRegister: usize = 9;
+const fpRegister: usize = 10;
+/// Return address of the most recent `call`.
+const lrRegister: usize = 18;
+/// Address of the instruction being run. Read only.
+const ipRegister: usize = 19;
+#[derive(Debug, Clone, PartialEq)]
+struct SourceLocation {
+    file: String,
+    line: usize,
+}
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
 #[derive(Debug)]
 struct IntermediateLanguageInstruction{
     instruction: String,
     arg1: String,
     arg2: String,
+    location: SourceLocation,
 }
 #[derive(Debug)]
 struct IntermediateLanguageLabel{
     label: String,
+    location: SourceLocation,
 }
 #[derive(Debug)]
 enum IntermediateLanguageLine{
@@ -30,22 +79,43 @@ enum IntermediateLanguageLine{
     Label(IntermediateLanguageLabel),
 }
 impl IntermediateLanguageLine{
-    fn parchLine(line: &str) -> IntermediateLanguageLine {
+    /// Splits a line on spaces, keeping a bracketed memory operand such as
+    /// `[r2 + 8]` together as one part without its inner spaces.
+    fn splitOperands(line: &str) -> Vec<String> {
+        let mut parts: Vec<String> = vec![];
+        let mut inBrackets = false;
+        for part in line.split(" ") {
+            if inBrackets {
+                parts.last_mut().unwrap().push_str(part);
+            } else {
+                parts.push(part.to_string());
+            }
+            if part.contains('[') {
+                inBrackets = true;
+            }
+            if part.contains(']') {
+                inBrackets = false;
+            }
+        }
+        parts
+    }
+    fn parchLine(line: &str, location: SourceLocation) -> Result<IntermediateLanguageLine, String> {
         if line.ends_with(":") {
-            let label = IntermediateLanguageLabel{label: line.to_string()};
-            IntermediateLanguageLine::Label(label)
+            let label = IntermediateLanguageLabel{label: line.to_string(), location};
+            Ok(IntermediateLanguageLine::Label(label))
         }else {
-            let splitLine = line.trim().split(" ").collect::<Vec<&str>>();
-            let instruction = if (stringInstructionsToU8.contains(&splitLine[0])){
+            let splitLine = IntermediateLanguageLine::splitOperands(line.trim());
+            let splitLine = splitLine.iter().map(|part| part.as_str()).collect::<Vec<&str>>();
+            let instruction = if instructions::byMnemonic(splitLine[0]).is_some() {
                 splitLine[0].to_string()
             }else {
-                panic!("invalid instruction");
+                return Err(format!("{}: unknown instruction `{}`", location, splitLine[0]));
             };
             let arg1 = splitLine.get(1).unwrap_or(&"").to_string();
             let arg2 = splitLine.get(2).unwrap_or(&"").to_string();
 
-            let line = IntermediateLanguageInstruction{instruction: instruction.to_string(), arg1, arg2};
-            IntermediateLanguageLine::Instruction(line)
+            let line = IntermediateLanguageInstruction{instruction: instruction.to_string(), arg1, arg2, location};
+            Ok(IntermediateLanguageLine::Instruction(line))
         }
 
     }
@@ -53,14 +123,78 @@ impl IntermediateLanguageLine{
 #[derive(Debug)]
 struct IntermediateLanguage{
     lines: Vec<IntermediateLanguageLine>,
+    entry: Option<(String, SourceLocation)>,
+    /// Labels named by `#export`, where they were named.
+    exports: Vec<(String, SourceLocation)>,
 }
-#[derive(Debug)]
+/// Size of one encoded `Line` record in bytecode and object files.
+const lineSize: usize = 20;
+#[derive(Debug, Clone)]
 struct Line {
     instruction: u8,
     arg1: u64,
     arg1IsReg: bool,
     arg2: u64,
     arg2IsReg: bool,
+    addressing: Addressing,
+}
+/// Describes a memory operand `[base+index*scale+disp]`. The displacement is
+/// the operand's own `arg1`/`arg2` value; at most one operand is a memory operand.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Addressing {
+    arg1IsMem: bool,
+    arg2IsMem: bool,
+    base: Option<u8>,
+    index: Option<u8>,
+    /// 1, 2, 4 or 8.
+    scale: u8,
+}
+impl Line {
+    /// Encodes the line as its 20-byte bytecode record: opcode, arg1 and arg2 as
+    /// little-endian u64, a flags byte holding the `IsReg` and `IsMem` bits, the
+    /// base register + 1 (0 for none) and the index register + 1 (0 for none)
+    /// with log2 of the scale in its top three bits.
+    fn encode(&self) -> [u8; lineSize] {
+        let mut buf = [0u8; lineSize];
+        buf[0] = self.instruction;
+        buf[1..9].copy_from_slice(&self.arg1.to_le_bytes());
+        buf[9..17].copy_from_slice(&self.arg2.to_le_bytes());
+        let mut flags: u8 = 0;
+        if self.arg1IsReg {
+            flags |= 1 << 0;
+        }
+        if self.arg2IsReg {
+            flags |= 1 << 1;
+        }
+        if self.addressing.arg1IsMem {
+            flags |= 1 << 2;
+        }
+        if self.addressing.arg2IsMem {
+            flags |= 1 << 3;
+        }
+        buf[17] = flags;
+        buf[18] = self.addressing.base.map_or(0, |base| base + 1);
+        buf[19] = self.addressing.index.map_or(0, |index| index + 1)
+            | ((self.addressing.scale.max(1).trailing_zeros() as u8) << 5);
+        buf
+    }
+    fn decode(buf: &[u8; lineSize]) -> Line {
+        let flags = buf[17];
+        Line {
+            instruction: buf[0],
+            arg1: u64::from_le_bytes(buf[1..9].try_into().unwrap()),
+            arg1IsReg: flags & 1 != 0,
+            arg2: u64::from_le_bytes(buf[9..17].try_into().unwrap()),
+            arg2IsReg: flags & 2 != 0,
+            addressing: Addressing {
+                arg1IsMem: flags & 4 != 0,
+                arg2IsMem: flags & 8 != 0,
+                base: buf[18].checked_sub(1),
+                index: (buf[19] & 0x1f).checked_sub(1),
+                scale: 1 << (buf[19] >> 5),
+            },
+        }
+    }
 }
 
 fn parse_include_file(path: &str) -> String {
@@ -74,40 +208,44 @@ fn parse_include_file(path: &str) -> String {
 
 
 struct Parcher {
-    lines: Vec<Line>,
-    instructionIndex: u64,
-    filtered: Vec<String>,
+    filtered: Vec<(String, SourceLocation)>,
     imports: Vec<String>,
-    labels: HashMap<String, u64>,
+    entry: Option<(String, SourceLocation)>,
+    exports: Vec<(String, SourceLocation)>,
 }
 impl Parcher {
     fn new() -> Parcher {
         Parcher {
-            lines: vec![],
-            instructionIndex: 0,
             filtered: vec![],
             imports: Vec::new(),
-            labels: HashMap::new(),
+            entry: None,
+            exports: vec![],
         }
     }
 
+    fn numberedLines(content: &str, file: &str) -> Vec<(String, SourceLocation)> {
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                (line.to_string(), SourceLocation { file: file.to_string(), line: index + 1 })
+            })
+            .collect()
+    }
 
-    fn parchFileToIntermediate(&mut self, path: &str)->IntermediateLanguage {
+    fn parchFileToIntermediate(&mut self, path: &str) -> Result<IntermediateLanguage, String> {
         let mut data_file = File::open(path).unwrap();
         let mut file_content = String::new();
         data_file.read_to_string(&mut file_content).unwrap();
-        let split: Vec<&str> = file_content.split("\n").collect();
-        let mut filteredStr: Vec<&str> = split
-            .iter()
-            .filter(|line| !line.trim().is_empty())
-            .cloned()
-            .collect();
-        self.filtered = filteredStr
-            .iter()
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
+        self.parchSource(&file_content, path)
+    }
 
-        for line in self.filtered.clone().iter() {
+    /// Parses assembly source, `path` naming it in source locations.
+    fn parchSource(&mut self, file_content: &str, path: &str) -> Result<IntermediateLanguage, String> {
+        self.filtered = Parcher::numberedLines(file_content, path);
+
+        for (line, location) in self.filtered.clone().iter() {
             let trimmed = line.trim();
             if trimmed.starts_with("#") {
                 let preprocessorInstruction = trimmed.split_whitespace().collect::<Vec<&str>>();
@@ -125,38 +263,214 @@ impl Parcher {
                         }
                         self.imports.push(filename);
                         let include_content = parse_include_file(preprocessorInstruction[1]);
-                        let include_lines: Vec<&str> = include_content
-                            .lines()
-                            .filter(|line| !line.trim().is_empty())
-                            .collect();
-                        let include_lines: Vec<String> =
-                            include_lines.iter().map(|line| line.to_string()).collect();
-                        self.filtered.extend(include_lines);
+                        self.filtered
+                            .extend(Parcher::numberedLines(&include_content, preprocessorInstruction[1]));
+                    }
+                    "entry" => {
+                        let Some(label) = preprocessorInstruction.get(1) else {
+                            return Err(format!("{}: #entry needs a label", location));
+                        };
+                        if let Some((_, previous)) = &self.entry {
+                            return Err(format!("{}: duplicate #entry, first given at {}", location, previous));
+                        }
+                        self.entry = Some((label.to_string(), location.clone()));
+                    }
+                    "export" => {
+                        if preprocessorInstruction.len() < 2 {
+                            return Err(format!("{}: #export needs at least one label", location));
+                        }
+                        for label in &preprocessorInstruction[1..] {
+                            self.exports.push((label.to_string(), location.clone()));
+                        }
                     }
                     "/" => continue,
-                    _ => todo!(),
+                    directive => return Err(format!("{}: unknown directive `#{}`", location, directive)),
                 }
             }
         }
-        let mut intermediatelanguage: IntermediateLanguage = IntermediateLanguage{lines:vec![]};
-        for line in self.filtered.clone().iter() {
+        let mut intermediatelanguage: IntermediateLanguage = IntermediateLanguage{lines:vec![], entry: self.entry.clone(), exports: self.exports.clone()};
+        for (line, location) in self.filtered.clone().into_iter() {
             if line.trim().is_empty() || line.trim().starts_with(";") || line.starts_with("#"){
                 continue;
             }
-            intermediatelanguage.lines.push(IntermediateLanguageLine::parchLine(line))
+            intermediatelanguage.lines.push(IntermediateLanguageLine::parchLine(&line, location)?)
         }
-        intermediatelanguage
+        Ok(intermediatelanguage)
     }
 }
 
-fn main() {
+fn assemble(asm_file: &str, matches: &ArgMatches) -> ByteCodeCompiler {
     let mut parcher = Parcher::new();
-    let intermetiate = parcher.parchFileToIntermediate("/home/niel/NIS/test.asm");
-    println!("{:?}", intermetiate);
-    let mut byteCodeCompiler = ByteCodeCompiler{lines:vec![],labels:HashMap::new(),instructionIndex:0};
-    byteCodeCompiler.compileByteCodeFromIntermediate(intermetiate);
-    byteCodeCompiler.run();
-    /*
+    let intermediate = parcher.parchFileToIntermediate(asm_file).unwrap_or_else(|error| {
+        eprintln!("error: {}", error);
+        process::exit(1);
+    });
+    let mut byteCodeCompiler = ByteCodeCompiler::new();
+    byteCodeCompiler.entryLabel = matches.get_one::<String>("entry").cloned();
+    byteCodeCompiler.library = matches.get_flag("lib");
+    byteCodeCompiler.object = matches.get_flag("object");
+    let result = byteCodeCompiler.compileByteCodeFromIntermediate(intermediate);
+    for warning in &byteCodeCompiler.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        process::exit(1);
+    }
+    if let Some(listing_file) = matches.get_one::<String>("listing")
+        && let Err(error) = listing::writeListing(listing_file, asm_file, &parcher.filtered, &byteCodeCompiler)
+    {
+        eprintln!("error: could not write listing {}: {}", listing_file, error);
+        process::exit(1);
+    }
+    byteCodeCompiler
+}
+
+/// Adds a linker input, which is an archive when it starts with the archive magic
+/// and an object file otherwise.
+fn addLinkInput(linker: &mut Linker, path: &str) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    let isArchive = File::open(path)?.read_exact(&mut magic).is_ok() && &magic == archiveMagic;
+    if isArchive {
+        linker.addArchive(path, Archive::readFromFile(path)?);
+    } else {
+        linker.addObject(path, ObjectFile::readFromFile(path)?);
+    }
+    Ok(())
+}
+
+/// Instructions the debugger keeps in its history unless `--history` says otherwise.
+const defaultHistory: usize = 1_000_000;
+
+/// Parses `--timeout`, a positive number of seconds that may have a fraction.
+fn parseTimeout(text: &str) -> Result<Duration, String> {
+    match text.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(format!("`{}` is not a positive number of seconds", text)),
+    }
+}
+
+/// Checks `--gdb`, `stdio` or a port number. Port 0 picks a free port.
+fn parseGdbTarget(text: &str) -> Result<String, String> {
+    match text == "stdio" || text.parse::<u16>().is_ok() {
+        true => Ok(text.to_string()),
+        false => Err(format!("`{}` is not a port number or `stdio`", text)),
+    }
+}
+
+fn run(byteCodeCompiler: &mut ByteCodeCompiler, file: &str, matches: &ArgMatches) {
+    if byteCodeCompiler.entry.is_none() {
+        eprintln!("error: {} has no entry point, it was built as a library", file);
+        process::exit(1);
+    }
+    byteCodeCompiler.stackSize = matches.get_one::<usize>("stack-size").copied().unwrap_or(defaultStackSize);
+    byteCodeCompiler.callMode = if matches.get_flag("stack-calls") {
+        CallMode::Stack
+    } else if matches.get_flag("link-calls") {
+        CallMode::Link
+    } else {
+        CallMode::Hidden
+    };
+    byteCodeCompiler.checkedHeap = matches.get_flag("checked-heap");
+    byteCodeCompiler.byteAddressing = matches.get_flag("byte-addressing");
+    byteCodeCompiler.limits = Limits {
+        maxInstructions: matches.get_one::<u64>("max-instructions").copied(),
+        maxHeapWords: matches.get_one::<usize>("max-memory").copied(),
+        maxCallDepth: matches.get_one::<usize>("max-call-depth").copied(),
+        timeout: matches.get_one::<Duration>("timeout").copied(),
+    };
+    let snapshot = matches.get_one::<String>("snapshot");
+    let restorePath = matches.get_one::<String>("restore");
+    let debug = matches.get_flag("debug");
+    let input = matches.get_one::<String>("input");
+    let gdb = matches.get_one::<String>("gdb");
+    if snapshot.is_none() && restorePath.is_none() && !debug && input.is_none() && gdb.is_none() {
+        if let Err(fault) = byteCodeCompiler.run() {
+            fail(&fault);
+        }
+        return;
+    }
+    let mut machine = match restorePath {
+        Some(path) => byteCodeCompiler.readSnapshotFromFile(path).unwrap_or_else(|error| {
+            if let Some(fault) = error.get_ref().and_then(|inner| inner.downcast_ref::<VmFault>()) {
+                fail(fault);
+            }
+            eprintln!("error: could not restore {}: {}", path, error);
+            process::exit(1);
+        }),
+        None => byteCodeCompiler.start().unwrap_or_else(|fault| fail(&fault)),
+    };
+    if let Some(path) = input {
+        match File::open(path) {
+            Ok(file) => machine.console.input = Some(Box::new(BufReader::new(file))),
+            Err(error) => {
+                eprintln!("error: could not open {}: {}", path, error);
+                process::exit(1);
+            }
+        }
+    }
+    // The restored run goes on from where the original stopped reading.
+    if let Some(path) = restorePath
+        && let Err(error) = machine.console.skipInput()
+    {
+        eprintln!("error: could not restore {}: {}", path, error);
+        process::exit(1);
+    }
+    if let Some(target) = gdb {
+        let served = match target.as_str() {
+            "stdio" => GdbStub::stdio(byteCodeCompiler, machine).serve(),
+            port => GdbStub::listen(byteCodeCompiler, machine, port.parse().unwrap_or_default()).and_then(|mut stub| stub.serve()),
+        };
+        if let Err(error) = served {
+            eprintln!("error: gdb connection failed: {}", error);
+            process::exit(1);
+        }
+        return;
+    }
+    if debug {
+        let historyCapacity = matches.get_one::<usize>("history").copied().unwrap_or(defaultHistory);
+        Debugger::new(byteCodeCompiler, machine, historyCapacity).run();
+        return;
+    }
+    let result = match (snapshot, matches.get_one::<u64>("snapshot-every")) {
+        (Some(path), Some(&every)) => checkpointed(byteCodeCompiler, &mut machine, path, every),
+        _ => byteCodeCompiler.resume(&mut machine),
+    };
+    if let Err(fault) = result {
+        if let Some(path) = snapshot {
+            saveSnapshot(byteCodeCompiler, &machine, path);
+        }
+        fail(&fault);
+    }
+    if let Some(code) = machine.exitCode {
+        process::exit(code);
+    }
+}
+
+fn fail(fault: &VmFault) -> ! {
+    eprintln!("error: {}", fault);
+    process::exit(fault.exitCode());
+}
+
+/// Runs to the end, writing a snapshot to `path` every `every` instructions.
+fn checkpointed(byteCodeCompiler: &mut ByteCodeCompiler, machine: &mut Machine, path: &str, every: u64) -> Result<(), VmFault> {
+    while byteCodeCompiler.isRunning(machine) {
+        byteCodeCompiler.step(machine)?;
+        if machine.executed.is_multiple_of(every) {
+            saveSnapshot(byteCodeCompiler, machine, path);
+        }
+    }
+    Ok(())
+}
+
+fn saveSnapshot(byteCodeCompiler: &ByteCodeCompiler, machine: &Machine, path: &str) {
+    if let Err(error) = byteCodeCompiler.writeSnapshotToFile(machine, path) {
+        eprintln!("error: could not write snapshot {}: {}", path, error);
+        process::exit(1);
+    }
+}
+
+fn main() {
     let matches = Command::new("NIS")
         .version("1.0")
         .author("You")
@@ -177,6 +491,51 @@ fn main() {
                 .help("Output file for compiled binary")
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("listing")
+                .short('l')
+                .long("listing")
+                .value_name("LIST_FILE")
+                .help("Write an assembler listing when assembling")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("entry")
+                .short('e')
+                .long("entry")
+                .value_name("LABEL")
+                .help("Label to start execution at, overriding #entry (default: main)")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("lib")
+                .long("lib")
+                .help("Assemble a library module without an entry point, loadable with interrupt 5")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("object")
+                .short('c')
+                .long("object")
+                .help("Assemble to a relocatable object file for the linker")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("link")
+                .long("link")
+                .value_name("OBJ_FILE")
+                .help("Link object files and archives into one executable binary")
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("archive")
+                .long("archive")
+                .value_name("OBJ_FILE")
+                .help("Bundle object files into a static library archive")
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
         .arg(
             Arg::new("run")
                 .short('r')
@@ -185,6 +544,133 @@ fn main() {
                 .help("Run a compiled binary file")
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("stack-size")
+                .long("stack-size")
+                .value_name("WORDS")
+                .help("Words of memory reserved for the stack when running (default: 1024)")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("stack-calls")
+                .long("stack-calls")
+                .help("Keep return addresses on the sp stack in memory instead of a hidden call stack")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("link-calls")
+                .long("link-calls")
+                .help("Return to the address in lr instead of popping one, leaving lr for the callee to save")
+                .conflicts_with("stack-calls")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("checked-heap")
+                .long("checked-heap")
+                .help("Detect double frees and use after free of heap blocks")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("byte-addressing")
+                .long("byte-addressing")
+                .help("Address memory in bytes instead of 64-bit words")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("snapshot")
+                .long("snapshot")
+                .value_name("FILE")
+                .help("Save the machine state to FILE when the run stops with a fault or reaches a limit")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("snapshot-every")
+                .long("snapshot-every")
+                .value_name("N")
+                .help("Also save the snapshot every N instructions")
+                .requires("snapshot")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("restore")
+                .long("restore")
+                .value_name("FILE")
+                .help("Resume the program from a snapshot instead of its entry point")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("input")
+                .long("input")
+                .value_name("FILE")
+                .help("Give the program FILE to read instead of standard input")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("debug")
+                .long("debug")
+                .help("Run under the interactive debugger, which can also step backwards")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("gdb")
+                .long("gdb")
+                .value_name("PORT|stdio")
+                .help("Serve the GDB remote protocol on 127.0.0.1:PORT, or on standard input and output")
+                .conflicts_with("debug")
+                .value_parser(parseGdbTarget)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("history")
+                .long("history")
+                .value_name("N")
+                .help("Instructions the debugger remembers for stepping backwards (default: 1000000)")
+                .requires("debug")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("max-instructions")
+                .long("max-instructions")
+                .value_name("N")
+                .help("Stop the run with a fault after executing N instructions")
+                .value_parser(clap::value_parser!(u64))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .long("max-memory")
+                .value_name("WORDS")
+                .help("Fault on an allocation that would leave more than WORDS heap words in use")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("max-call-depth")
+                .long("max-call-depth")
+                .value_name("N")
+                .help("Fault on a call made while N calls are already unreturned")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Stop the run with a fault after SECONDS of wall clock time")
+                .value_parser(parseTimeout)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("disassemble")
+                .short('d')
+                .long("disassemble")
+                .value_name("BIN_FILE")
+                .help("Print the instructions of a compiled binary file")
+                .action(ArgAction::Set),
+        )
         .arg(
             Arg::new("interpret")
                 .short('i')
@@ -193,6 +679,13 @@ fn main() {
                 .help("Interpret an ASM file directly")
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("reference")
+                .long("reference")
+                .value_name("HTML_FILE")
+                .help("Regenerate the instruction reference section of the HTML docs")
+                .action(ArgAction::Set),
+        )
         .get_matches();
 
     // Assemble
@@ -203,34 +696,100 @@ fn main() {
             .unwrap_or_else(|| "file.bin".to_string());
         println!("Assembling {} -> {}", asm_file, output_file);
 
-        let mut parcher = Parcher::new();
-        parcher.parchFile(asm_file);
-        parcher.writeToFile(output_file.as_str());
+        let mut byteCodeCompiler = assemble(asm_file, &matches);
+        if byteCodeCompiler.object {
+            if let Err(error) = ObjectFile::fromCompiler(&byteCodeCompiler).writeToFile(&output_file) {
+                eprintln!("error: could not write {}: {}", output_file, error);
+                process::exit(1);
+            }
+        } else {
+            byteCodeCompiler.writeToFile(output_file.as_str());
+        }
         println!("Assembled successfully!");
     }
+    // Link object files
+    else if let Some(object_files) = matches.get_many::<String>("link") {
+        let output_file = matches
+            .get_one::<String>("output")
+            .cloned()
+            .unwrap_or_else(|| "file.bin".to_string());
+        let mut linker = Linker::new();
+        linker.entryLabel = matches.get_one::<String>("entry").cloned();
+        linker.library = matches.get_flag("lib");
+        for object_file in object_files {
+            if let Err(error) = addLinkInput(&mut linker, object_file) {
+                eprintln!("error: could not read {}: {}", object_file, error);
+                process::exit(1);
+            }
+        }
+        match linker.link() {
+            Ok(mut image) => image.writeToFile(output_file.as_str()),
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("error: {}", error);
+                }
+                process::exit(1);
+            }
+        }
+        println!("Linked successfully!");
+    }
+    // Bundle object files into an archive
+    else if let Some(object_files) = matches.get_many::<String>("archive") {
+        let output_file = matches
+            .get_one::<String>("output")
+            .cloned()
+            .unwrap_or_else(|| "lib.a".to_string());
+        let mut archive = Archive::new();
+        for object_file in object_files {
+            match ObjectFile::readFromFile(object_file) {
+                Ok(object) => {
+                    let name = Path::new(object_file).file_name().unwrap().to_string_lossy();
+                    archive.addMember(&name, object);
+                }
+                Err(error) => {
+                    eprintln!("error: could not read {}: {}", object_file, error);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Err(error) = archive.writeToFile(&output_file) {
+            eprintln!("error: could not write {}: {}", output_file, error);
+            process::exit(1);
+        }
+        println!("Archived successfully!");
+    }
     // Run compiled binary
     else if let Some(bin_file) = matches.get_one::<String>("run") {
-        //println!("Running binary {}", bin_file);
-        let mut parcher = Parcher::new();
-        parcher.readFromFile(bin_file);
-        let mut interpreter = Interpreter {
-            lines: parcher.lines,
-        };
-        interpreter.run();
+        let mut byteCodeCompiler = ByteCodeCompiler::new();
+        if let Err(error) = byteCodeCompiler.readFromFile(bin_file) {
+            eprintln!("error: could not read {}: {}", bin_file, error);
+            process::exit(1);
+        }
+        run(&mut byteCodeCompiler, bin_file, &matches);
         println!();
     }
+    // Disassemble compiled binary
+    else if let Some(bin_file) = matches.get_one::<String>("disassemble") {
+        let mut byteCodeCompiler = ByteCodeCompiler::new();
+        if let Err(error) = byteCodeCompiler.readFromFile(bin_file) {
+            eprintln!("error: could not read {}: {}", bin_file, error);
+            process::exit(1);
+        }
+        print!("{}", disassembler::disassemble(&byteCodeCompiler));
+    }
     // Interpret ASM file directly
     else if let Some(asm_file) = matches.get_one::<String>("interpret") {
-        //println!("Interpreting ASM file {}", asm_file);
-        let mut parcher = Parcher::new();
-        parcher.parchFile(asm_file);
-        let mut interpreter = Interpreter {
-            lines: parcher.lines,
-        };
-        interpreter.run();
+        let mut byteCodeCompiler = assemble(asm_file, &matches);
+        run(&mut byteCodeCompiler, asm_file, &matches);
+    }
+    // Regenerate the instruction reference in the docs
+    else if let Some(html_file) = matches.get_one::<String>("reference") {
+        if let Err(error) = instructions::writeReference(html_file) {
+            eprintln!("error: {}", error);
+            process::exit(1);
+        }
+        println!("Updated the instruction reference in {}", html_file);
     } else {
         println!("No valid option provided. Use -h for help.");
     }
-    */
 }
-
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use crate::backends::{byteCodeMagic, loadBytes, ByteCodeCompiler};
+use crate::object::ObjectFile;
+use crate::verifier::verifyLine;
+
+/// Interrupt code that loads a module at runtime.
+pub const loadModuleInterrupt: u8 = 5;
+/// Returned in `r7` when a module or label cannot be loaded.
+pub const loadModuleFailed: u64 = u64::MAX;
+
+/// Reads a zero-terminated string stored one character per word, or per byte
+/// with byte addressing, returning it with the address just past its terminator.
+fn readMemString(mem: &[u64], address: u64, byteAddressing: bool) -> Result<(String, u64), String> {
+    let mut string = String::new();
+    let mut address = address;
+    loop {
+        let character = if byteAddressing {
+            loadBytes(mem, address, 1)
+        } else {
+            mem.get(address as usize).copied()
+        };
+        let Some(word) = character else {
+            return Err(format!("string at {} runs past the end of memory", address));
+        };
+        address += 1;
+        if word == 0 {
+            return Ok((string, address));
+        }
+        match char::from_u32(word as u32) {
+            Some(character) => string.push(character),
+            None => return Err(format!("invalid character {} in string", word)),
+        }
+    }
+}
+
+/// Reads a module, either an object file or bytecode assembled with `--lib`.
+/// Bytecode has no imports; its relocations are relative to its first
+/// instruction, which it gets as an unnamed local label.
+fn readModule(path: &str) -> io::Result<ObjectFile> {
+    let mut magic = [0u8; 4];
+    File::open(path)?.read_exact(&mut magic)?;
+    if &magic != byteCodeMagic {
+        return ObjectFile::readFromFile(path);
+    }
+    let mut module = ByteCodeCompiler::new();
+    module.readFromFile(path)?;
+    Ok(ObjectFile {
+        entryLabel: None,
+        symbols: module.exportedSymbols(),
+        locals: vec![(String::new(), 0)],
+        imports: vec![],
+        lines: module.lines,
+        relocations: module.relocations,
+    })
+}
+
+impl ByteCodeCompiler {
+    /// Handles the load module interrupt. `address` points at the module path
+    /// followed by the name of the exported label, both zero-terminated. The
+    /// module is relocated to the end of the instruction space on first use.
+    pub fn loadModule(&mut self, mem: &[u64], address: u64, byteAddressing: bool) -> Result<u64, String> {
+        let (path, next) = readMemString(mem, address, byteAddressing)?;
+        let (label, _) = readMemString(mem, next, byteAddressing)?;
+        self.addModule(&path)?;
+        match self.modules[&path].get(&label) {
+            Some(&target) => Ok(target as u64),
+            None => Err(format!("module {} does not export `{}`", path, label)),
+        }
+    }
+
+    /// Relocates the module at `path` into the program unless it already is.
+    pub fn addModule(&mut self, path: &str) -> Result<(), String> {
+        if !self.modules.contains_key(path) {
+            let symbols = self.relocateModule(path)?;
+            self.modules.insert(path.to_string(), symbols);
+            self.moduleOrder.push(path.to_string());
+        }
+        Ok(())
+    }
+
+    /// Appends the module's code to the program and patches its relocations.
+    /// Imports are resolved against the labels the running program exports.
+    fn relocateModule(&mut self, path: &str) -> Result<HashMap<String, usize>, String> {
+        let object = readModule(path).map_err(|error| format!("could not load {}: {}", path, error))?;
+        let base = self.lines.len();
+        let symbols: HashMap<String, usize> = object
+            .symbols
+            .iter()
+            .map(|(name, address)| (name.clone(), base + address))
+            .collect();
+        let own = object.labelsAt(base);
+        let mut lines = object.lines.clone();
+        for relocation in &object.relocations {
+            let exported = || self.exports.contains(&relocation.symbol).then(|| self.labels.get(&relocation.symbol)).flatten();
+            let Some(&target) = own.get(relocation.symbol.as_str()).or_else(exported) else {
+                return Err(format!("module {} imports `{}`, which the program does not export", path, relocation.symbol));
+            };
+            relocation.apply(&mut lines[relocation.index], target);
+        }
+        let length = base + lines.len();
+        for (index, line) in lines.iter().enumerate() {
+            verifyLine(line, length).map_err(|error| format!("module {}: instruction {}: {}", path, index, error))?;
+        }
+        self.sourceMap.extend(lines.iter().map(|_| None));
+        self.lines.extend(lines);
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parcher;
+
+    fn compile(source: &str, object: bool, library: bool) -> ByteCodeCompiler {
+        let intermediate = Parcher::new().parchSource(source, "test.asm").unwrap();
+        let mut compiler = ByteCodeCompiler::new();
+        compiler.object = object;
+        compiler.library = library;
+        compiler.compileByteCodeFromIntermediate(intermediate).unwrap();
+        compiler
+    }
+
+    /// A path in the temporary directory that no other test uses.
+    fn temporary(name: &str) -> String {
+        std::env::temp_dir().join(format!("nis-{}-{}", std::process::id(), name)).to_string_lossy().to_string()
+    }
+
+    /// Memory holding `strings` one character per word, each zero-terminated.
+    fn strings(strings: &[&str]) -> Vec<u64> {
+        strings.iter().flat_map(|string| string.chars().map(|character| character as u64).chain([0])).collect()
+    }
+
+    const host: &str = "#export hostfn\nmain:\nhlt\nhostfn:\nret";
+
+    #[test]
+    fn importsResolveAgainstTheProgramsExports() {
+        let path = temporary("imports.o");
+        ObjectFile::fromCompiler(&compile("#export f\nf:\ncall hostfn\nret", true, true)).writeToFile(&path).unwrap();
+        let mut program = compile(host, false, false);
+        let target = program.loadModule(&strings(&[&path, "f"]), 0, false).unwrap();
+        assert_eq!(target, 2);
+        assert_eq!(program.lines[2].arg1, 1);
+
+        let mut program = compile("main:\nhlt\nhostfn:\nret", false, false);
+        let error = program.loadModule(&strings(&[&path, "f"]), 0, false).err().unwrap();
+        assert_eq!(error, format!("module {} imports `hostfn`, which the program does not export", path));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn modulesAreLoadedOnce() {
+        let path = temporary("once.o");
+        ObjectFile::fromCompiler(&compile("#export f g\nf:\njmp g\ng:\nret", true, true)).writeToFile(&path).unwrap();
+        let mut program = compile(host, false, false);
+        assert_eq!(program.loadModule(&strings(&[&path, "f"]), 0, false), Ok(2));
+        assert_eq!(program.loadModule(&strings(&[&path, "g"]), 0, false), Ok(3));
+        assert_eq!(program.lines.len(), 4);
+        assert_eq!(program.moduleOrder, vec![path.clone()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn libraryBytecodeIsRebased() {
+        let path = temporary("library.bin");
+        compile("#export f\nf:\ncall g\nret\ng:\nret", false, true).writeToFile(&path);
+        let mut program = compile(host, false, false);
+        assert_eq!(program.loadModule(&strings(&[&path, "f"]), 0, false), Ok(2));
+        assert_eq!(program.lines[2].arg1, 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failedLoadsReturnTheFailureValue() {
+        let mut program = compile("main:\nset r8 5\nint 0\nhlt", false, false);
+        let mut machine = program.start().unwrap();
+        machine.mem = strings(&[&temporary("missing.o"), "f"]);
+        program.resume(&mut machine).unwrap();
+        assert_eq!(machine.registers[crate::resultRegister], loadModuleFailed);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use crate::backends::{ByteCodeCompiler, Operand, Relocation};
+use crate::{lineSize, Line};
+
+/// Object files start with this magic and a format version byte, followed by
+/// the entry label, the code, the exported symbols, the local labels, the
+/// imported symbols and the relocations. Counts are little-endian u64, strings are a u16 length and UTF-8.
+const objectMagic: &[u8; 4] = b"NISO";
+const objectVersion: u8 = 3;
+
+/// A relocatable module: code whose label operands are still described by
+/// relocations, so it can be placed at any address by the linker.
+#[derive(Debug)]
+pub struct ObjectFile {
+    pub entryLabel: Option<String>,
+    pub lines: Vec<Line>,
+    /// Exported labels with their module-relative addresses: the `#export`
+    /// labels and the entry label.
+    pub symbols: Vec<(String, usize)>,
+    /// The other labels, which only the module's own relocations can refer to.
+    pub locals: Vec<(String, usize)>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    pub fn fromCompiler(compiler: &ByteCodeCompiler) -> ObjectFile {
+        // The linker starts at `main` unless told otherwise, so it is exported like `#entry`.
+        let entryLabel = compiler.entryLabel.as_deref().unwrap_or("main");
+        let (mut symbols, mut locals): (Vec<_>, Vec<_>) = compiler
+            .labels
+            .iter()
+            .map(|(name, address)| (name.clone(), *address))
+            .partition(|(name, _)| name == entryLabel || compiler.exports.contains(name));
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        locals.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        ObjectFile {
+            entryLabel: compiler.entryLabel.clone(),
+            lines: compiler.lines.clone(),
+            symbols,
+            locals,
+            imports: compiler.imports.clone(),
+            relocations: compiler.relocations.clone(),
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(objectMagic)?;
+        writer.write_all(&[objectVersion])?;
+        writeString(writer, self.entryLabel.as_deref().unwrap_or(""))?;
+        writeCount(writer, self.lines.len())?;
+        for line in &self.lines {
+            writer.write_all(&line.encode())?;
+        }
+        for labels in [&self.symbols, &self.locals] {
+            writeCount(writer, labels.len())?;
+            for (name, address) in labels {
+                writeString(writer, name)?;
+                writeCount(writer, *address)?;
+            }
+        }
+        writeCount(writer, self.imports.len())?;
+        for name in &self.imports {
+            writeString(writer, name)?;
+        }
+        writeCount(writer, self.relocations.len())?;
+        for relocation in &self.relocations {
+            writeCount(writer, relocation.index)?;
+            writer.write_all(&[match relocation.operand {
+                Operand::Arg1 => 1,
+                Operand::Arg2 => 2,
+            }])?;
+            writeString(writer, &relocation.symbol)?;
+            writer.write_all(&relocation.addend.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<ObjectFile> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != objectMagic {
+            return Err(invalid("not a NIS object file".to_string()));
+        }
+        let version = readByte(reader)?;
+        if version != objectVersion {
+            return Err(invalid(format!("unsupported object version {}", version)));
+        }
+        let entryLabel = Some(readString(reader)?).filter(|label| !label.is_empty());
+        let mut lines = vec![];
+        for _ in 0..readCount(reader)? {
+            let mut buf = [0u8; lineSize];
+            reader.read_exact(&mut buf)?;
+            lines.push(Line::decode(&buf));
+        }
+        let mut symbols = vec![];
+        for _ in 0..readCount(reader)? {
+            let name = readString(reader)?;
+            symbols.push((name, readCount(reader)?));
+        }
+        let mut locals = vec![];
+        for _ in 0..readCount(reader)? {
+            let name = readString(reader)?;
+            locals.push((name, readCount(reader)?));
+        }
+        let mut imports = vec![];
+        for _ in 0..readCount(reader)? {
+            imports.push(readString(reader)?);
+        }
+        let mut relocations = vec![];
+        for _ in 0..readCount(reader)? {
+            let index = readCount(reader)?;
+            let operand = match readByte(reader)? {
+                1 => Operand::Arg1,
+                2 => Operand::Arg2,
+                other => return Err(invalid(format!("invalid relocation operand {}", other))),
+            };
+            if index >= lines.len() {
+                return Err(invalid(format!("relocation of instruction {} outside the code", index)));
+            }
+            let symbol = readString(reader)?;
+            relocations.push(Relocation {
+                index,
+                operand,
+                symbol,
+                addend: readCount(reader)? as u64,
+                location: None,
+            });
+        }
+        Ok(ObjectFile {
+            entryLabel,
+            lines,
+            symbols,
+            locals,
+            imports,
+            relocations,
+        })
+    }
+
+    /// Every label of the module, exported or not, at `base` plus its address.
+    /// Relocations look here before the symbols of other modules.
+    pub fn labelsAt(&self, base: usize) -> HashMap<&str, usize> {
+        self.symbols.iter().chain(&self.locals).map(|(name, address)| (name.as_str(), base + address)).collect()
+    }
+
+    pub fn writeToFile(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn readFromFile(path: &str) -> io::Result<ObjectFile> {
+        ObjectFile::read(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn writeCount(writer: &mut impl Write, count: usize) -> io::Result<()> {
+    writer.write_all(&(count as u64).to_le_bytes())
+}
+
+pub fn writeString(writer: &mut impl Write, string: &str) -> io::Result<()> {
+    writer.write_all(&(string.len() as u16).to_le_bytes())?;
+    writer.write_all(string.as_bytes())
+}
+
+pub fn readByte(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn readCount(reader: &mut impl Read) -> io::Result<usize> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf) as usize)
+}
+
+pub fn readString(reader: &mut impl Read) -> io::Result<String> {
+    let mut length = [0u8; 2];
+    reader.read_exact(&mut length)?;
+    let mut buf = vec![0u8; u16::from_le_bytes(length) as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid("symbol name is not UTF-8".to_string()))
+}
@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::Instant;
+use crate::backends::{ByteCodeCompiler, CallMode, Flags};
+use crate::fault::VmFault;
+use crate::heap::Heap;
+use crate::machine::{Console, Machine};
+use crate::object::{readByte, readCount, readString, writeCount, writeString};
+use crate::registerCount;
+
+/// Snapshot files start with this magic and a format version byte, followed by
+/// the hash of the program they belong to, the run's settings, the machine
+/// state, the heap, the console positions and the runtime modules in load
+/// order. Numbers are little-endian u64 unless noted.
+const snapshotMagic: &[u8; 4] = b"NISS";
+const snapshotVersion: u8 = 1;
+
+/// FNV-1a hash of the entry point and encoded instructions. Taken when a run
+/// starts, before any runtime modules are loaded, and a snapshot only
+/// restores onto a program with the same hash.
+pub fn programHash(program: &ByteCodeCompiler) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let entry = program.entry.map_or(u64::MAX, |entry| entry as u64).to_le_bytes();
+    for byte in entry.into_iter().chain(program.lines.iter().flat_map(|line| line.encode())) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn writeFlag(writer: &mut impl Write, flag: bool) -> io::Result<()> {
+    writer.write_all(&[flag as u8])
+}
+
+fn readFlag(reader: &mut impl Read) -> io::Result<bool> {
+    match readByte(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(invalid(format!("invalid flag byte {}", other))),
+    }
+}
+
+impl ByteCodeCompiler {
+    pub fn writeSnapshot(&self, machine: &Machine, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(snapshotMagic)?;
+        writer.write_all(&[snapshotVersion])?;
+        writer.write_all(&machine.programHash.to_le_bytes())?;
+        writeCount(writer, self.stackSize)?;
+        writer.write_all(&[match self.callMode {
+            CallMode::Hidden => 0,
+            CallMode::Stack => 1,
+            CallMode::Link => 2,
+        }])?;
+        writeFlag(writer, self.checkedHeap)?;
+        writeFlag(writer, self.byteAddressing)?;
+
+        writeCount(writer, machine.ip)?;
+        writer.write_all(&machine.executed.to_le_bytes())?;
+        writeCount(writer, machine.callDepth)?;
+        writeFlag(writer, machine.carrierbit)?;
+        let flags = machine.flags;
+        for flag in [flags.zero, flags.carry, flags.overflow, flags.negative] {
+            writeFlag(writer, flag)?;
+        }
+        // Stored as a u64 so an exit code of -1 can't be mistaken for none.
+        writer.write_all(&machine.exitCode.map_or(u64::MAX, |code| code as u32 as u64).to_le_bytes())?;
+        writeCount(writer, registerCount)?;
+        for register in machine.registers {
+            writer.write_all(&register.to_le_bytes())?;
+        }
+        writeCount(writer, machine.callStack.len())?;
+        for &address in &machine.callStack {
+            writeCount(writer, address)?;
+        }
+        writeCount(writer, machine.mem.len())?;
+        for word in &machine.mem {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+        machine.heap.write(writer)?;
+        writer.write_all(&machine.console.inputPosition.to_le_bytes())?;
+        writer.write_all(&machine.console.outputPosition.to_le_bytes())?;
+        writeCount(writer, self.moduleOrder.len())?;
+        for path in &self.moduleOrder {
+            writeString(writer, path)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a snapshot of a run of this program, taking over the run's
+    /// settings and loading its runtime modules again. Standard input is not
+    /// touched; see `Console::skipInput`. A program the verifier rejects is an
+    /// error wrapping `VmFault::InvalidBytecode`, as `start` would return.
+    pub fn readSnapshot(&mut self, reader: &mut impl Read) -> io::Result<Machine> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != snapshotMagic {
+            return Err(invalid("not a NIS snapshot".to_string()));
+        }
+        let version = readByte(reader)?;
+        if version != snapshotVersion {
+            return Err(invalid(format!("unsupported snapshot version {}", version)));
+        }
+        let hash = readCount(reader)? as u64;
+        if !self.modules.is_empty() || hash != programHash(self) {
+            return Err(invalid("the snapshot was taken of a different program".to_string()));
+        }
+        self.stackSize = readCount(reader)?;
+        self.callMode = match readByte(reader)? {
+            0 => CallMode::Hidden,
+            1 => CallMode::Stack,
+            2 => CallMode::Link,
+            other => return Err(invalid(format!("invalid call mode {}", other))),
+        };
+        self.checkedHeap = readFlag(reader)?;
+        self.byteAddressing = readFlag(reader)?;
+
+        let ip = readCount(reader)?;
+        let executed = readCount(reader)? as u64;
+        let callDepth = readCount(reader)?;
+        let carrierbit = readFlag(reader)?;
+        let flags = Flags {
+            zero: readFlag(reader)?,
+            carry: readFlag(reader)?,
+            overflow: readFlag(reader)?,
+            negative: readFlag(reader)?,
+        };
+        let exitCode = match readCount(reader)? as u64 {
+            u64::MAX => None,
+            code => Some(code as u32 as i32),
+        };
+        let registers = readCount(reader)?;
+        if registers != registerCount {
+            return Err(invalid(format!("snapshot has {} registers, not {}", registers, registerCount)));
+        }
+        let mut machine = Machine {
+            registers: [0; registerCount],
+            mem: vec![],
+            carrierbit,
+            flags,
+            callStack: vec![],
+            heap: Heap::new(self.checkedHeap, if self.byteAddressing { 8 } else { 1 }, self.limits.maxHeapWords),
+            ip,
+            executed,
+            callDepth,
+            console: Console::default(),
+            exitCode,
+            programHash: hash,
+            started: Instant::now(),
+        };
+        for register in &mut machine.registers {
+            *register = readCount(reader)? as u64;
+        }
+        for _ in 0..readCount(reader)? {
+            machine.callStack.push(readCount(reader)?);
+        }
+        let words = readCount(reader)?;
+        if words <= self.stackSize {
+            return Err(invalid(format!("memory of {} words is smaller than the stack", words)));
+        }
+        for _ in 0..words {
+            machine.mem.push(readCount(reader)? as u64);
+        }
+        machine.heap.read(reader)?;
+        machine.console.inputPosition = readCount(reader)? as u64;
+        machine.console.outputPosition = readCount(reader)? as u64;
+        for _ in 0..readCount(reader)? {
+            self.addModule(&readString(reader)?).map_err(invalid)?;
+        }
+        if machine.ip > self.lines.len() {
+            return Err(invalid(format!("ip {} is past the end of the program", machine.ip)));
+        }
+        if let Err(errors) = self.verify() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, VmFault::InvalidBytecode { errors }));
+        }
+        Ok(machine)
+    }
+
+    pub fn writeSnapshotToFile(&self, machine: &Machine, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.writeSnapshot(machine, &mut writer)?;
+        writer.flush()
+    }
+
+    pub fn readSnapshotFromFile(&mut self, path: &str) -> io::Result<Machine> {
+        self.readSnapshot(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parcher;
+
+    fn compile(source: &str) -> ByteCodeCompiler {
+        let intermediate = Parcher::new().parchSource(source, "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        program
+    }
+
+    /// Everything a snapshot keeps, for comparing a machine with its restored copy.
+    fn state(machine: &Machine) -> String {
+        let mut heap = vec![];
+        machine.heap.write(&mut heap).unwrap();
+        format!(
+            "{:?} {:?} {} {:?} {:?} {} {} {} {:?} {:?} {} {}",
+            machine.registers,
+            machine.mem,
+            machine.carrierbit,
+            machine.flags,
+            machine.callStack,
+            machine.ip,
+            machine.executed,
+            machine.callDepth,
+            machine.exitCode,
+            heap,
+            machine.console.inputPosition,
+            machine.console.outputPosition
+        )
+    }
+
+    /// Allocates, pushes, prints and reads, then stops inside a call at `inside`.
+    const interrupted: &str = "main:\nset r8 1\nint 4\nmov r6 r7\nstore r6 42\npush 5\ncmp 1 2\nset r8 6\nint -12\n\
+                          set r8 11\nint 0\ncall f\nhlt\nf:\neq 1 1\ninside:\nret";
+
+    #[test]
+    fn restoringGivesBackTheSameMachine() {
+        let mut program = compile(interrupted);
+        let mut machine = program.start().unwrap();
+        machine.console.input = Some(Box::new(io::Cursor::new(b"ab".to_vec())));
+        machine.console.output = Some(Box::new(io::sink()));
+        while machine.ip != program.labels["inside"] {
+            program.step(&mut machine).unwrap();
+        }
+        let mut snapshot = vec![];
+        program.writeSnapshot(&machine, &mut snapshot).unwrap();
+
+        let mut restoring = compile(interrupted);
+        let mut restored = restoring.readSnapshot(&mut snapshot.as_slice()).unwrap();
+        assert_eq!(state(&restored), state(&machine));
+        assert_eq!((restored.console.inputPosition, restored.console.outputPosition), (1, 3));
+        assert_eq!(restored.callStack, vec![11]);
+
+        restored.console.input = Some(Box::new(io::Cursor::new(b"ab".to_vec())));
+        restored.console.skipInput().unwrap();
+        restored.console.output = Some(Box::new(io::sink()));
+        program.resume(&mut machine).unwrap();
+        restoring.resume(&mut restored).unwrap();
+        assert_eq!(state(&restored), state(&machine));
+    }
+
+    #[test]
+    fn restoredProgramsAreVerified() {
+        let good = compile("main:\nmov r1 r2\nhlt");
+        let mut machine = good.start().unwrap();
+        let mut bad = compile("main:\nmov r1 r2\nhlt");
+        bad.lines[0].arg2 = 50;
+        machine.programHash = programHash(&bad);
+        let mut snapshot = vec![];
+        bad.writeSnapshot(&machine, &mut snapshot).unwrap();
+        let error = bad.readSnapshot(&mut snapshot.as_slice()).err().unwrap();
+        let fault = error.get_ref().and_then(|inner| inner.downcast_ref::<VmFault>()).unwrap();
+        assert_eq!(fault, &VmFault::InvalidBytecode { errors: bad.verify().err().unwrap() });
+        assert_eq!(fault.exitCode(), 208);
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+use crate::backends::ByteCodeCompiler;
+use crate::instructions::{byOpcode, OperandKind};
+use crate::{ipRegister, registerCount, Line};
+
+/// A problem found in a program before it runs. `index` is the instruction it
+/// was found in, `None` for problems with the program as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "instruction {}: {}", index, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+fn registerName(register: u64) -> String {
+    format!("register {}, but there are only {}", register, registerCount)
+}
+
+/// Checks one instruction of a program `length` instructions long: a valid
+/// opcode, the operands its spec asks for, registers that exist and jump
+/// targets inside the program. Jumping to `length`, just past the last
+/// instruction, ends the program and is allowed.
+pub fn verifyLine(line: &Line, length: usize) -> Result<(), String> {
+    let Some(spec) = byOpcode(line.instruction) else {
+        return Err(format!("invalid opcode {}", line.instruction));
+    };
+    let error = |message: String| Err(format!("`{}` {}", spec.mnemonic, message));
+    let addressing = &line.addressing;
+    if addressing.arg1IsMem && addressing.arg2IsMem {
+        return error("has two memory operands".to_string());
+    }
+    let operands = [
+        ("first", spec.operands.0, line.arg1, line.arg1IsReg, addressing.arg1IsMem),
+        ("second", spec.operands.1, line.arg2, line.arg2IsReg, addressing.arg2IsMem),
+    ];
+    for (ordinal, kind, value, isReg, isMem) in operands {
+        if isMem && kind != OperandKind::Address {
+            return error(format!("cannot take a memory operand as its {} operand", ordinal));
+        }
+        if isReg && value >= registerCount as u64 {
+            return error(format!("{} operand names {}", ordinal, registerName(value)));
+        }
+        match kind {
+            OperandKind::None if isReg || value != 0 => {
+                return error(format!("takes no {} operand", ordinal));
+            }
+            OperandKind::Register if !isReg => {
+                return error(format!("needs a register as its {} operand", ordinal));
+            }
+            OperandKind::Register if value == ipRegister as u64 => {
+                return error("cannot write the read only `ip` register".to_string());
+            }
+            OperandKind::Target if !isReg && value > length as u64 => {
+                return error(format!("target {} is past the end of the program at {}", value, length));
+            }
+            _ => {}
+        }
+    }
+    let hasMemoryOperand = addressing.arg1IsMem || addressing.arg2IsMem;
+    if !hasMemoryOperand && (addressing.base.is_some() || addressing.index.is_some()) {
+        return error("has a base or index register but no memory operand".to_string());
+    }
+    for register in [addressing.base, addressing.index].into_iter().flatten() {
+        if register as usize >= registerCount {
+            return error(format!("memory operand names {}", registerName(register as u64)));
+        }
+    }
+    if hasMemoryOperand && !matches!(addressing.scale, 1 | 2 | 4 | 8) {
+        return error(format!("memory operand has index scale {}, not 1, 2, 4 or 8", addressing.scale));
+    }
+    Ok(())
+}
+
+impl ByteCodeCompiler {
+    /// Verifies every instruction and the entry point, collecting all problems.
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        let length = self.lines.len();
+        let mut errors: Vec<VerifyError> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter_map(|(index, line)| verifyLine(line, length).err().map(|message| VerifyError { index: Some(index), message }))
+            .collect();
+        if let Some(entry) = self.entry
+            && entry > length
+        {
+            errors.push(VerifyError {
+                index: None,
+                message: format!("entry point {} is past the end of the program at {}", entry, length),
+            });
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parcher;
+
+    fn compile(source: &str) -> ByteCodeCompiler {
+        let intermediate = Parcher::new().parchSource(&format!("main:\n{}\nhlt", source), "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        program
+    }
+
+    /// Verifies the first instruction of `source` after `change` broke it.
+    fn verifyChanged(source: &str, change: impl FnOnce(&mut Line)) -> Result<(), String> {
+        let program = compile(source);
+        let mut line = program.lines[0].clone();
+        assert_eq!(verifyLine(&line, program.lines.len()), Ok(()));
+        change(&mut line);
+        verifyLine(&line, program.lines.len())
+    }
+
+    #[test]
+    fn opcodesMustExist() {
+        assert_eq!(verifyChanged("mov r1 r2", |line| line.instruction = 200), Err("invalid opcode 200".to_string()));
+    }
+
+    #[test]
+    fn registersMustExist() {
+        let error = verifyChanged("mov r1 r2", |line| line.arg2 = 50);
+        assert_eq!(error, Err("`mov` second operand names register 50, but there are only 20".to_string()));
+        let error = verifyChanged("load r1 [r2]", |line| line.addressing.base = Some(20));
+        assert_eq!(error, Err("`load` memory operand names register 20, but there are only 20".to_string()));
+    }
+
+    #[test]
+    fn targetsMayNotPassTheEnd() {
+        assert_eq!(verifyChanged("jmp main", |line| line.arg1 = 2), Ok(()));
+        let error = verifyChanged("jmp main", |line| line.arg1 = 3);
+        assert_eq!(error, Err("`jmp` target 3 is past the end of the program at 2".to_string()));
+        // Register targets are only known when the jump runs.
+        let registerTarget = |line: &mut Line| (line.arg1, line.arg1IsReg) = (3, true);
+        assert_eq!(verifyChanged("jmp main", registerTarget), Ok(()));
+    }
+
+    #[test]
+    fn registerOperandsMustBeRegisters() {
+        let error = verifyChanged("mov r1 r2", |line| line.arg1IsReg = false);
+        assert_eq!(error, Err("`mov` needs a register as its first operand".to_string()));
+        let error = verifyChanged("hlt", |line| line.arg1 = 1);
+        assert_eq!(error, Err("`hlt` takes no first operand".to_string()));
+    }
+
+    #[test]
+    fn ipIsReadOnly() {
+        let error = verifyChanged("mov r1 r2", |line| line.arg1 = ipRegister as u64);
+        assert_eq!(error, Err("`mov` cannot write the read only `ip` register".to_string()));
+        assert_eq!(verifyChanged("mov r1 r2", |line| line.arg2 = ipRegister as u64), Ok(()));
+    }
+
+    #[test]
+    fn memoryOperandsAreChecked() {
+        let error = verifyChanged("load r1 [r2+r3*2]", |line| line.addressing.scale = 3);
+        assert_eq!(error, Err("`load` memory operand has index scale 3, not 1, 2, 4 or 8".to_string()));
+        let error = verifyChanged("store [r2] r1", |line| line.addressing.arg2IsMem = true);
+        assert_eq!(error, Err("`store` has two memory operands".to_string()));
+        let error = verifyChanged("mov r1 r2", |line| line.addressing.arg2IsMem = true);
+        assert_eq!(error, Err("`mov` cannot take a memory operand as its second operand".to_string()));
+        let error = verifyChanged("mov r1 r2", |line| line.addressing.base = Some(2));
+        assert_eq!(error, Err("`mov` has a base or index register but no memory operand".to_string()));
+    }
+
+    #[test]
+    fn verifyReportsEveryProblemAndTheEntryPoint() {
+        let mut program = compile("mov r1 r2\nmov r1 r2");
+        program.lines[0].arg2 = 50;
+        program.lines[1].instruction = 200;
+        program.entry = Some(4);
+        assert_eq!(
+            program.verify(),
+            Err(vec![
+                VerifyError { index: Some(0), message: "`mov` second operand names register 50, but there are only 20".to_string() },
+                VerifyError { index: Some(1), message: "invalid opcode 200".to_string() },
+                VerifyError { index: None, message: "entry point 4 is past the end of the program at 3".to_string() },
+            ])
+        );
+        program.entry = Some(3);
+        assert_eq!(program.verify().err().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,333 @@
+use std::fmt;
+use crate::backends::{parseNumber, ByteCodeCompiler};
+use crate::machine::Machine;
+use crate::stringToReg;
+
+/// Something a condition compares: a register, a memory word or a number.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Register(usize),
+    /// A memory address in the program's units.
+    Memory(u64),
+    Number(u64),
+}
+
+impl Value {
+    fn parse(text: &str) -> Result<Value, String> {
+        if let Some(register) = stringToReg.iter().position(|&register| register == text) {
+            return Ok(Value::Register(register));
+        }
+        if let Some(address) = text.strip_prefix('[').and_then(|address| address.strip_suffix(']')) {
+            return parseNumber(address).map(Value::Memory).ok_or_else(|| format!("invalid address `{}`", address));
+        }
+        parseNumber(text)
+            .map(Value::Number)
+            .ok_or_else(|| format!("`{}` is not a register, [address] or number", text))
+    }
+
+    /// The value now, `None` for memory past the end.
+    fn get(&self, machine: &Machine) -> Option<u64> {
+        match *self {
+            Value::Register(register) => Some(machine.register(register)),
+            Value::Memory(address) => machine.mem.get((address / machine.heap.wordSize()) as usize).copied(),
+            Value::Number(number) => Some(number),
+        }
+    }
+}
+
+/// An unsigned comparison between two values, such as `r1 == 42` or `sp > 100`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    lhs: Value,
+    comparison: &'static str,
+    rhs: Value,
+}
+
+/// Longest first, so `<=` isn't taken for `<`.
+const comparisons: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let Some((index, comparison)) = comparisons.iter().find_map(|&comparison| text.find(comparison).map(|index| (index, comparison))) else {
+            return Err(format!("`{}` has no comparison, use one of {}", text, comparisons.join(" ")));
+        };
+        Ok(Condition {
+            lhs: Value::parse(text[..index].trim())?,
+            comparison,
+            rhs: Value::parse(text[index + comparison.len()..].trim())?,
+        })
+    }
+
+    /// Whether the condition holds. Memory past the end makes it false.
+    pub fn holds(&self, machine: &Machine) -> bool {
+        let (Some(lhs), Some(rhs)) = (self.lhs.get(machine), self.rhs.get(machine)) else {
+            return false;
+        };
+        match self.comparison {
+            "==" => lhs == rhs,
+            "!=" => lhs != rhs,
+            "<=" => lhs <= rhs,
+            ">=" => lhs >= rhs,
+            "<" => lhs < rhs,
+            _ => lhs > rhs,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Register(register) => write!(f, "{}", stringToReg[*register]),
+            Value::Memory(address) => write!(f, "[{}]", address),
+            Value::Number(number) => write!(f, "{}", number),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.lhs, self.comparison, self.rhs)
+    }
+}
+
+/// A memory word to stop at when an instruction reads or writes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    /// Address in the program's units, as it was given.
+    pub address: u64,
+    pub read: bool,
+    pub write: bool,
+}
+
+enum Watch {
+    Memory(Watchpoint),
+    /// With whether the condition held after the last instruction, so it
+    /// stops only where it becomes true.
+    Condition(Condition, bool),
+}
+
+/// The watched words an instruction is about to touch, taken before it runs.
+#[derive(Default)]
+pub struct Pending {
+    /// Indices of watchpoints it reads.
+    reads: Vec<usize>,
+    /// Indices of watchpoints it may write, with the word's value before.
+    writes: Vec<(usize, Option<u64>)>,
+    /// A heap interrupt, which only counts as a write where a value changed.
+    heap: bool,
+}
+
+/// Watchpoints and conditions checked around each instruction. Running them is
+/// up to the caller: `before` and `after` wrap a step, and a run that never
+/// calls them, like a plain `run`, pays nothing for them.
+#[derive(Default)]
+pub struct Watches {
+    watches: Vec<Watch>,
+}
+
+impl Watches {
+    pub fn isEmpty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    pub fn addWatchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watches.push(Watch::Memory(watchpoint));
+    }
+
+    /// Adds a condition that stops the run when it becomes true, counting
+    /// from the state of `machine` now.
+    pub fn addCondition(&mut self, condition: Condition, machine: &Machine) {
+        let held = condition.holds(machine);
+        self.watches.push(Watch::Condition(condition, held));
+    }
+
+    /// Removes the watch numbered `number` in `list`.
+    pub fn remove(&mut self, number: usize) -> Result<(), String> {
+        if number == 0 || number > self.watches.len() {
+            return Err(format!("no watch {}", number));
+        }
+        self.watches.remove(number - 1);
+        Ok(())
+    }
+
+    /// Descriptions of the watches, numbered from 1.
+    pub fn list(&self) -> Vec<String> {
+        self.watches
+            .iter()
+            .enumerate()
+            .map(|(index, watch)| match watch {
+                Watch::Memory(watchpoint) => {
+                    let access = match (watchpoint.read, watchpoint.write) {
+                        (true, true) => "access",
+                        (true, false) => "read",
+                        _ => "write",
+                    };
+                    format!("{}: {} of [{}]", index + 1, access, watchpoint.address)
+                }
+                Watch::Condition(condition, _) => format!("{}: {}", index + 1, condition),
+            })
+            .collect()
+    }
+
+    /// Call before running the instruction at `machine.ip`.
+    pub fn before(&self, program: &ByteCodeCompiler, machine: &Machine) -> Pending {
+        let mut pending = Pending::default();
+        if !self.watches.iter().any(|watch| matches!(watch, Watch::Memory(_))) {
+            return pending;
+        }
+        let wordSize = machine.heap.wordSize();
+        let reads = program.readWords(machine);
+        let written = program.writtenWords(machine);
+        pending.heap = written.is_none();
+        for (index, watch) in self.watches.iter().enumerate() {
+            let Watch::Memory(watchpoint) = watch else {
+                continue;
+            };
+            let word = (watchpoint.address / wordSize) as usize;
+            if watchpoint.read && reads.contains(&word) {
+                pending.reads.push(index);
+            }
+            if watchpoint.write && written.as_ref().is_none_or(|written| written.contains(&word)) {
+                pending.writes.push((index, machine.mem.get(word).copied()));
+            }
+        }
+        pending
+    }
+
+    /// Call after the instruction ran, with what `before` returned for it.
+    /// Returns a line for each watch it triggered, empty when none did.
+    pub fn after(&mut self, machine: &Machine, pending: Pending, ip: usize) -> Vec<String> {
+        let mut hits = vec![];
+        let wordSize = machine.heap.wordSize();
+        for index in pending.reads {
+            if let Watch::Memory(watchpoint) = &self.watches[index] {
+                hits.push(format!("read of [{}] at ip {}", watchpoint.address, ip));
+            }
+        }
+        for (index, old) in pending.writes {
+            if let Watch::Memory(watchpoint) = &self.watches[index] {
+                let new = machine.mem.get((watchpoint.address / wordSize) as usize).copied();
+                if pending.heap && new == old {
+                    continue;
+                }
+                let show = |value: Option<u64>| value.map_or("none".to_string(), |value| value.to_string());
+                hits.push(format!("write of [{}] at ip {}: {} -> {}", watchpoint.address, ip, show(old), show(new)));
+            }
+        }
+        hits.extend(self.conditions(machine));
+        hits
+    }
+
+    /// Updates whether each condition holds, returning those that just became true.
+    pub fn conditions(&mut self, machine: &Machine) -> Vec<String> {
+        let mut hits = vec![];
+        for watch in &mut self.watches {
+            if let Watch::Condition(condition, held) = watch {
+                let holds = condition.holds(machine);
+                if holds && !*held {
+                    hits.push(format!("{} became true", condition));
+                }
+                *held = holds;
+            }
+        }
+        hits
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watches.iter().filter_map(|watch| match watch {
+            Watch::Memory(watchpoint) => Some(watchpoint),
+            Watch::Condition(..) => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parcher;
+
+    fn compile(source: &str) -> ByteCodeCompiler {
+        let intermediate = Parcher::new().parchSource(source, "test.asm").unwrap();
+        let mut program = ByteCodeCompiler::new();
+        program.compileByteCodeFromIntermediate(intermediate).unwrap();
+        program
+    }
+
+    /// Runs `source` to its end with `watches`, returning every hit with the ip the run stopped at.
+    fn hits(source: &str, addWatches: impl FnOnce(&mut Watches, &Machine)) -> Vec<(String, usize)> {
+        let mut program = compile(source);
+        let mut machine = program.start().unwrap();
+        let mut watches = Watches::default();
+        addWatches(&mut watches, &machine);
+        let mut hits = vec![];
+        while program.isRunning(&machine) {
+            let pending = watches.before(&program, &machine);
+            let ip = machine.ip;
+            program.step(&mut machine).unwrap();
+            hits.extend(watches.after(&machine, pending, ip).into_iter().map(|hit| (hit, machine.ip)));
+        }
+        hits
+    }
+
+    #[test]
+    fn conditionsParse() {
+        let condition = Condition::parse("r1 <= [16]").unwrap();
+        assert_eq!(condition, Condition { lhs: Value::Register(1), comparison: "<=", rhs: Value::Memory(16) });
+        assert_eq!(condition.to_string(), "r1 <= [16]");
+        assert_eq!(Condition::parse("sp>0x10").unwrap().to_string(), "sp > 16");
+        assert_eq!(Condition::parse("r1 = 2"), Err("`r1 = 2` has no comparison, use one of == != <= >= < >".to_string()));
+        assert_eq!(Condition::parse("r99 == 2"), Err("`r99` is not a register, [address] or number".to_string()));
+        assert_eq!(Condition::parse("[x] == 2"), Err("invalid address `x`".to_string()));
+    }
+
+    #[test]
+    fn conditionsCompareUnsigned() {
+        let program = compile("main:\nhlt");
+        let mut machine = program.start().unwrap();
+        machine.registers[1] = u64::MAX;
+        machine.mem[3] = 7;
+        let holds = |text: &str, machine: &Machine| Condition::parse(text).unwrap().holds(machine);
+        assert!(holds("r1 > 1", &machine));
+        assert!(holds("[3] == 7", &machine));
+        assert!(holds("[3] != r1", &machine));
+        assert!(holds("7 >= [3]", &machine));
+        assert!(!holds("[3] < 7", &machine));
+        // Memory past the end never compares.
+        assert!(!holds("[1000000] == 0", &machine));
+        assert!(!holds("[1000000] != 0", &machine));
+    }
+
+    #[test]
+    fn conditionsSeeTheCurrentIp() {
+        let source = "main:\nset r1 1\nset r1 2\nset r1 3\nhlt";
+        let hits = hits(source, |watches, machine| watches.addCondition(Condition::parse("ip == 2").unwrap(), machine));
+        assert_eq!(hits, vec![("ip == 2 became true".to_string(), 2)]);
+        let mut program = compile(source);
+        let mut machine = program.start().unwrap();
+        program.step(&mut machine).unwrap();
+        program.step(&mut machine).unwrap();
+        assert_eq!(machine.register(crate::ipRegister), 2);
+    }
+
+    #[test]
+    fn conditionsStopOnlyWhereTheyBecomeTrue() {
+        let source = "main:\nset r1 5\nset r1 6\nset r1 1\nset r1 7\nhlt";
+        let hits = hits(source, |watches, machine| watches.addCondition(Condition::parse("r1 > 4").unwrap(), machine));
+        assert_eq!(hits, vec![("r1 > 4 became true".to_string(), 1), ("r1 > 4 became true".to_string(), 4)]);
+    }
+
+    #[test]
+    fn watchpointsReportReadsAndWrites() {
+        let source = "main:\nset r1 5\nstore 10 r1\nload r2 [10]\nstore 11 r1\nstore 10 r1\nhlt";
+        let watch = |read, write| {
+            move |watches: &mut Watches, _: &Machine| watches.addWatchpoint(Watchpoint { address: 10, read, write })
+        };
+        let writes = vec![
+            ("write of [10] at ip 1: 0 -> 5".to_string(), 2),
+            ("write of [10] at ip 4: 5 -> 5".to_string(), 5),
+        ];
+        assert_eq!(hits(source, watch(false, true)), writes);
+        assert_eq!(hits(source, watch(true, false)), vec![("read of [10] at ip 2".to_string(), 3)]);
+        assert_eq!(hits(source, watch(true, true)).len(), 3);
+    }
+}